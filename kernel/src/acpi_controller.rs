@@ -190,26 +190,74 @@ impl AcpiController {
 }
 
 impl AcpiHandler for AcpiMemoryHandler {
+    /// Maps the table into its own `Acpi` region of the kernel address space, so the table
+    /// mappings show up in the region manager. Falls back to the physical memory mapping if
+    /// that fails, `unmap_physical_region` leaves those alone.
     unsafe fn map_physical_region<T>(
         &mut self,
         physical_address: usize,
         size: usize
     ) -> PhysicalMapping<T> {
-        // `physical_address` might not be page aligned, so padding might be needed
-        // The size of the allocated memory needs to be the same as or bigger than size_of::<T>()
-        // `size` should contain the size of T in bytes, I think, so I'll simply allocate that
+        use crate::memory::{mapping, vma};
+
+        // `physical_address` might not be page aligned, the whole pages around it are mapped
+        let page_offset = physical_address as u64 & (Size4KiB::SIZE - 1);
+        let phys_page = PhysAddr::new(physical_address as u64 - page_offset);
+        let pages = (page_offset + size as u64 + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+
+        let mapped = vma::allocate(pages, 1, vma::RegionKind::Acpi, "ACPI table").and_then(|region| {
+            let mapped = {
+                let mut mapper = crate::memory::MAPPER.lock();
+                let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.lock();
+                match (mapper.as_mut(), frame_allocator.as_mut()) {
+                    (Some(mapper), Some(frame_allocator)) => mapping::map_range(
+                        region.start(),
+                        phys_page,
+                        pages * Size4KiB::SIZE,
+                        Flags::PRESENT | crate::memory::protection::no_execute(),
+                        mapper,
+                        frame_allocator,
+                    ).is_ok(),
+                    _ => false,
+                }
+            };
+            // The region is of no use unmapped, whether the mapping failed or was never tried
+            if mapped {
+                Some(region.start())
+            } else {
+                vma::free(region.start());
+                None
+            }
+        });
 
-        let virtual_start = self.phys_mem_offset + physical_address as u64;
+        let (virtual_start, mapped_length) = match mapped {
+            Some(start) => (start.as_u64() + page_offset, (pages * Size4KiB::SIZE - page_offset) as usize),
+            None => (self.phys_mem_offset + physical_address as u64, size),
+        };
 
         PhysicalMapping {
             physical_start: physical_address,
             virtual_start: core::ptr::NonNull::new_unchecked(virtual_start as *mut u8 as *mut T),
             region_length: size,
-            mapped_length: size,
+            mapped_length,
         }
     }
 
     fn unmap_physical_region<T>(&mut self, region: PhysicalMapping<T>) {
-        // Unmap the given physical region
+        use crate::memory::{mapping, vma};
+
+        let start = VirtAddr::from_ptr(region.virtual_start.as_ptr()).align_down(Size4KiB::SIZE);
+        let region = match vma::find(start) {
+            Some(region) if region.kind() == vma::RegionKind::Acpi && region.start() == start => region,
+            // Mapped through the physical memory mapping
+            _ => return,
+        };
+        {
+            let mut mapper = crate::memory::MAPPER.lock();
+            // The frames belong to the firmware, they are not returned to the frame allocator
+            mapping::unmap_range(region.start(), region.size(), mapper.as_mut().unwrap(), |_, _| {})
+                .expect("failed to unmap ACPI table");
+        }
+        vma::free(region.start());
     }
 }
@@ -0,0 +1,150 @@
+//! Heap corruption detection, enabled by the `alloc-hardening` feature.
+//!
+//! Every allocation is surrounded by red zones filled with a canary pattern, and freed memory
+//! is overwritten with a poison pattern. Frees check the canaries and the state of the block,
+//! so overflows and double frees are caught at the free instead of crashing somewhere else.
+//!
+//! A block looks like this, the front red zone is padded to the alignment of the allocation:
+//!
+//! ```text
+//! | free list link | state | size | canary ... | user data | canary |
+//! ```
+
+use core::alloc::Layout;
+use core::cmp::max;
+
+/// Written into red zones, damage to it means something wrote outside of its allocation
+const CANARY: u8 = 0xFD;
+/// Written into freed memory, so use after free reads stand out
+const POISON: u8 = 0xDD;
+
+const STATE_ALLOCATED: u64 = 0xA110_CA7E_D0D0_CAFE;
+const STATE_FREED: u64 = 0xF4EE_D0D0_DEAD_BEEF;
+
+/// A freed block starts with the slab free list link or the hole of the linked list heap, so
+/// these bytes can't be relied on after a free
+const LINK_SIZE: usize = 16;
+const STATE_OFFSET: usize = 16;
+const SIZE_OFFSET: usize = 24;
+const HEADER_SIZE: usize = 32;
+/// Smallest amount of canary bytes on either side of the user data
+const MIN_RED_ZONE: usize = 8;
+
+/// Where an allocation ends up in its block.
+struct BlockLayout {
+    /// Layout of the whole block
+    block: Layout,
+    /// Offset of the user data in the block
+    front: usize,
+}
+
+impl BlockLayout {
+    fn new(layout: Layout) -> Option<Self> {
+        let align = max(layout.align(), 8);
+        let front = align_up(HEADER_SIZE + MIN_RED_ZONE, align);
+        let size = front.checked_add(layout.size())?.checked_add(MIN_RED_ZONE)?;
+        Some(Self {
+            block: Layout::from_size_align(size, align).ok()?,
+            front,
+        })
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Allocation
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Allocates a block for `layout` with `alloc` and sets up its header and red zones.
+pub(super) unsafe fn alloc(layout: Layout, alloc: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+    let block_layout = match BlockLayout::new(layout) {
+        Some(block_layout) => block_layout,
+        None => return core::ptr::null_mut(),
+    };
+    let block = alloc(block_layout.block);
+    if block.is_null() {
+        return block;
+    }
+
+    let user = block.add(block_layout.front);
+    block.add(HEADER_SIZE).write_bytes(CANARY, block_layout.front - HEADER_SIZE);
+    user.add(layout.size()).write_bytes(CANARY, block_layout.block.size() - block_layout.front - layout.size());
+    (block.add(STATE_OFFSET) as *mut u64).write(STATE_ALLOCATED);
+    (block.add(SIZE_OFFSET) as *mut u64).write(layout.size() as u64);
+    user
+}
+
+/// Checks the block of the allocation at `ptr`, poisons it and frees it with `dealloc`.
+/// Panics with a description of the allocation if the block is damaged or already freed.
+pub(super) unsafe fn dealloc(ptr: *mut u8, layout: Layout, dealloc: impl FnOnce(*mut u8, Layout)) {
+    let block_layout = BlockLayout::new(layout).expect("freeing a layout that could never be allocated");
+    let block = ptr.sub(block_layout.front);
+
+    match (block.add(STATE_OFFSET) as *const u64).read() {
+        STATE_ALLOCATED => {}
+        STATE_FREED => report(ptr, layout, "double free"),
+        _ => report(ptr, layout, "free of a block that was never allocated, or its header was overwritten"),
+    }
+    let size = (block.add(SIZE_OFFSET) as *const u64).read() as usize;
+    if size != layout.size() {
+        report(ptr, layout, "freed with a different size than it was allocated with");
+    }
+    if let Some(offset) = find_damage(block.add(HEADER_SIZE), block_layout.front - HEADER_SIZE) {
+        let offset = block_layout.front - HEADER_SIZE - offset;
+        report_damage(ptr, layout, "underflow", -(offset as isize));
+    }
+    let back_size = block_layout.block.size() - block_layout.front - layout.size();
+    if let Some(offset) = find_damage(ptr.add(layout.size()), back_size) {
+        report_damage(ptr, layout, "overflow", (layout.size() + offset) as isize);
+    }
+
+    // Everything but the free list link gets poisoned, the state survives until reuse
+    block.add(LINK_SIZE).write_bytes(POISON, block_layout.block.size() - LINK_SIZE);
+    (block.add(STATE_OFFSET) as *mut u64).write(STATE_FREED);
+    dealloc(block, block_layout.block);
+}
+
+/// Returns the offset of the first byte in the red zone that does not hold the canary.
+unsafe fn find_damage(red_zone: *const u8, size: usize) -> Option<usize> {
+    core::slice::from_raw_parts(red_zone, size).iter().position(|&byte| byte != CANARY)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Reporting
+///////////////////////////////////////////////////////////////////////////////////////////////////
+fn report_damage(ptr: *mut u8, layout: Layout, kind: &str, offset: isize) -> ! {
+    panic!(
+        "HEAP CORRUPTION: {} of the allocation at {:p} ({:?}), first damaged byte at offset {}{}",
+        kind, ptr, layout, offset, Callers(ptr),
+    );
+}
+
+fn report(ptr: *mut u8, layout: Layout, problem: &str) -> ! {
+    panic!("HEAP CORRUPTION: {} at {:p} ({:?}){}", problem, ptr, layout, Callers(ptr));
+}
+
+/// Formats the callers of the allocation at a pointer, if allocation tracking knows them.
+struct Callers(*mut u8);
+
+impl core::fmt::Display for Callers {
+    #[cfg(feature = "alloc-tracking")]
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match super::tracking::find(self.0 as usize) {
+            Some(record) => {
+                write!(f, ", allocated by thread {:?} from", record.thread)?;
+                for &caller in record.callers.iter().take_while(|&&caller| caller != 0) {
+                    write!(f, " {:#x}", caller)?;
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(feature = "alloc-tracking"))]
+    fn fmt(&self, _f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        Ok(())
+    }
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,457 @@
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, MapperAllSizes, Page, PageTableFlags,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use linked_list_allocator::Heap;
+
+use crate::memory::{address_space, vma, BitmapFrameAllocator};
+
+#[cfg(feature = "alloc-hardening")]
+pub mod hardening;
+#[cfg(feature = "alloc-tracking")]
+pub mod tracking;
+
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+pub const HEAP_SIZE: usize = 128 * 1024; // 128 KiB, initial size
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB, ceiling for heap growth
+
+#[cfg_attr(not(feature = "alloc-tracking"), global_allocator)]
+static ALLOCATOR: SlabAllocator = SlabAllocator::empty();
+
+#[cfg(feature = "alloc-tracking")]
+#[global_allocator]
+static TRACKING_ALLOCATOR: tracking::TrackingAllocator = tracking::TrackingAllocator::new(&ALLOCATOR);
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+static HEAP_MAPPED: AtomicUsize = AtomicUsize::new(0);
+static HEAP_USED: AtomicUsize = AtomicUsize::new(0);
+static HEAP_HIGH_WATER_MARK: AtomicUsize = AtomicUsize::new(0);
+
+/// Maps the heap to physical pages and the initializes the heap allocator
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    map_heap_pages(VirtAddr::new(HEAP_START as u64), HEAP_SIZE, mapper, frame_allocator)?;
+
+    unsafe {
+        ALLOCATOR.heap.lock().large.init(HEAP_START, HEAP_SIZE);
+    }
+    HEAP_MAPPED.store(HEAP_SIZE, Ordering::SeqCst);
+
+    // Claim the whole range the heap can grow into, now that the heap can back the bookkeeping
+    let heap_pages = (HEAP_MAX_SIZE / Page::<Size4KiB>::SIZE as usize) as u64;
+    vma::reserve(VirtAddr::new(HEAP_START as u64), heap_pages, vma::RegionKind::Heap, "kernel heap")
+        .expect("heap range is already in use");
+
+    Ok(())
+}
+
+fn map_heap_pages(
+    start: VirtAddr,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_end = start + size - 1u64;
+        let heap_start_page = Page::containing_address(start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    let first_page = page_range.start;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | crate::memory::protection::no_execute();
+    for (mapped, page) in page_range.enumerate() {
+        let result = match frame_allocator.allocate_frame() {
+            Some(frame) => unsafe {
+                mapper.map_to(page, frame, flags, frame_allocator)
+                    .map(|flush| flush.flush())
+                    .map_err(|err| {
+                        frame_allocator.deallocate_frame(frame);
+                        err
+                    })
+            },
+            None => Err(MapToError::FrameAllocationFailed),
+        };
+        if let Err(err) = result {
+            // Give back what was mapped so far, so the range can be mapped again later
+            for page in Page::range(first_page, first_page + mapped as u64) {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    address_space::kernel_mapping_changed();
+                    unsafe { frame_allocator.deallocate_frame(frame); }
+                }
+            }
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Heap statistics and limits
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Amount of bytes currently mapped for the heap.
+pub fn heap_size() -> usize {
+    HEAP_MAPPED.load(Ordering::Relaxed)
+}
+
+/// Amount of bytes currently handed out by the heap.
+pub fn heap_used() -> usize {
+    HEAP_USED.load(Ordering::Relaxed)
+}
+
+/// The highest amount of bytes that have been handed out at the same time.
+pub fn heap_high_water_mark() -> usize {
+    HEAP_HIGH_WATER_MARK.load(Ordering::Relaxed)
+}
+
+/// The size the heap is allowed to grow to.
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Changes the size the heap is allowed to grow to, up to `HEAP_MAX_SIZE`. Lowering the
+/// limit below the current heap size does not shrink the heap, it only stops further growth.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(core::cmp::min(limit, HEAP_MAX_SIZE), Ordering::Relaxed);
+}
+
+/// A snapshot of the heap usage, in bytes. The mapped size splits into the allocated, overhead
+/// and free bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes mapped for the heap
+    pub size: usize,
+    /// Bytes requested by the live allocations
+    pub allocated: usize,
+    /// Bytes in use beyond the requested sizes: the rounding up to slab block sizes and the
+    /// padding and bookkeeping of the linked list heap
+    pub overhead: usize,
+    /// Bytes the allocator can still hand out: free slab blocks and the free space of the
+    /// linked list heap
+    pub free: usize,
+    /// Free bytes in slab blocks, which only allocations of their size class can use
+    pub fragmented: usize,
+    pub high_water_mark: usize,
+    pub limit: usize,
+}
+
+impl HeapStats {
+    /// Share of the free bytes that is fragmented.
+    pub fn fragmentation_percent(&self) -> usize {
+        if self.free == 0 { 0 } else { self.fragmented * 100 / self.free }
+    }
+}
+
+/// Collects the current heap statistics.
+pub fn heap_stats() -> HeapStats {
+    let heap = ALLOCATOR.heap.lock();
+    let size = heap_size();
+    let allocated = heap_used();
+    let free_blocks = heap.slabs
+        .iter()
+        .map(|slab| (slab.stats.total_blocks - slab.stats.used_blocks) * slab.stats.block_size)
+        .sum::<usize>();
+    let free = heap.large.free() + free_blocks;
+    HeapStats {
+        size,
+        allocated,
+        // The allocated bytes are counted outside the heap lock, so they can be slightly off
+        overhead: size.saturating_sub(allocated + free),
+        free,
+        fragmented: free_blocks,
+        high_water_mark: heap_high_water_mark(),
+        limit: heap_limit(),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Slab allocator
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Block sizes of the slabs. Allocations that don't fit in the biggest slab go to the
+/// linked list heap directly.
+const SLAB_SIZES: [usize; SLAB_COUNT] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const SLAB_COUNT: usize = 9;
+
+/// Size of the chunks slabs take from the linked list heap when they run out of blocks
+const SLAB_CHUNK_SIZE: usize = 4096;
+
+/// Per size class statistics.
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub block_size: usize,
+    pub total_blocks: usize,
+    pub used_blocks: usize,
+    pub total_allocations: usize,
+}
+
+/// Statistics for allocations that are too large for the slabs.
+#[derive(Debug, Clone, Copy)]
+pub struct LargeStats {
+    pub live_allocations: usize,
+    pub bytes_in_use: usize,
+    pub total_allocations: usize,
+}
+
+/// Statistics of every slab size class.
+pub fn slab_stats() -> [SlabStats; SLAB_COUNT] {
+    let heap = ALLOCATOR.heap.lock();
+    let mut stats = [heap.slabs[0].stats; SLAB_COUNT];
+    for (stats, slab) in stats.iter_mut().zip(heap.slabs.iter()) {
+        *stats = slab.stats;
+    }
+    stats
+}
+
+/// Statistics of the allocations that bypass the slabs.
+pub fn large_stats() -> LargeStats {
+    ALLOCATOR.heap.lock().large_stats
+}
+
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// A free list of equally sized blocks, carved out of chunks of the linked list heap.
+/// Blocks are aligned to their size, which is a power of two.
+struct Slab {
+    free_list: Option<NonNull<FreeBlock>>,
+    stats: SlabStats,
+}
+
+impl Slab {
+    const fn new(block_size: usize) -> Self {
+        Self {
+            free_list: None,
+            stats: SlabStats {
+                block_size,
+                total_blocks: 0,
+                used_blocks: 0,
+                total_allocations: 0,
+            },
+        }
+    }
+
+    /// Splits a chunk into blocks and adds them to the free list.
+    ///
+    /// This function is unsafe because the caller must guarantee that the chunk is unused,
+    /// `size` bytes large and aligned to the block size.
+    unsafe fn add_chunk(&mut self, chunk: NonNull<u8>, size: usize) {
+        let block_size = self.stats.block_size;
+        for offset in (0..size / block_size).rev().map(|i| i * block_size) {
+            self.push(NonNull::new_unchecked(chunk.as_ptr().add(offset)));
+        }
+        self.stats.total_blocks += size / block_size;
+    }
+
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        self.free_list.map(|block| {
+            self.free_list = unsafe { block.as_ref().next };
+            self.stats.used_blocks += 1;
+            self.stats.total_allocations += 1;
+            block.cast()
+        })
+    }
+
+    unsafe fn push(&mut self, ptr: NonNull<u8>) {
+        let block: NonNull<FreeBlock> = ptr.cast();
+        block.as_ptr().write(FreeBlock { next: self.free_list });
+        self.free_list = Some(block);
+    }
+
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>) {
+        self.push(ptr);
+        self.stats.used_blocks -= 1;
+    }
+}
+
+struct SlabHeap {
+    slabs: [Slab; SLAB_COUNT],
+    large: Heap,
+    large_stats: LargeStats,
+}
+
+// The free lists only point into the heap, which is owned by the allocator
+unsafe impl Send for SlabHeap {}
+
+impl SlabHeap {
+    /// Finds the slab for the given layout. Blocks are aligned to their size, so the
+    /// block has to be at least as large as the alignment.
+    fn slab_index(layout: &Layout) -> Option<usize> {
+        let size = core::cmp::max(layout.size(), layout.align());
+        SLAB_SIZES.iter().position(|&block_size| block_size >= size)
+    }
+
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        match Self::slab_index(&layout) {
+            Some(index) => {
+                if self.slabs[index].free_list.is_none() {
+                    let block_size = self.slabs[index].stats.block_size;
+                    let chunk_layout = Layout::from_size_align(SLAB_CHUNK_SIZE, block_size).unwrap();
+                    let chunk = self.allocate_large(chunk_layout)?;
+                    unsafe { self.slabs[index].add_chunk(chunk, SLAB_CHUNK_SIZE); }
+                }
+                self.slabs[index].pop()
+            }
+            None => {
+                let ptr = self.allocate_large(layout)?;
+                self.large_stats.live_allocations += 1;
+                self.large_stats.bytes_in_use += layout.size();
+                self.large_stats.total_allocations += 1;
+                Some(ptr)
+            }
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        match Self::slab_index(&layout) {
+            Some(index) => self.slabs[index].deallocate(ptr),
+            None => {
+                self.large.deallocate(ptr, layout);
+                self.large_stats.live_allocations -= 1;
+                self.large_stats.bytes_in_use -= layout.size();
+            }
+        }
+    }
+
+    fn allocate_large(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        loop {
+            if let Ok(ptr) = self.large.allocate_first_fit(layout) {
+                return Some(ptr);
+            }
+            // Padding for alignment and the free list bookkeeping might need a bit more
+            grow_heap(&mut self.large, layout.size() + layout.align()).ok()?;
+        }
+    }
+}
+
+/// The global allocator. Small allocations are served from power of two slabs, larger ones
+/// from a linked list heap that maps more pages behind its end when it runs out of memory,
+/// up to the limit set by `set_heap_limit`.
+pub struct SlabAllocator {
+    heap: Mutex<SlabHeap>,
+}
+
+impl SlabAllocator {
+    pub const fn empty() -> Self {
+        Self {
+            heap: Mutex::new(SlabHeap {
+                slabs: [
+                    Slab::new(SLAB_SIZES[0]), Slab::new(SLAB_SIZES[1]), Slab::new(SLAB_SIZES[2]),
+                    Slab::new(SLAB_SIZES[3]), Slab::new(SLAB_SIZES[4]), Slab::new(SLAB_SIZES[5]),
+                    Slab::new(SLAB_SIZES[6]), Slab::new(SLAB_SIZES[7]), Slab::new(SLAB_SIZES[8]),
+                ],
+                large: Heap::empty(),
+                large_stats: LargeStats {
+                    live_allocations: 0,
+                    bytes_in_use: 0,
+                    total_allocations: 0,
+                },
+            }),
+        }
+    }
+}
+
+impl SlabAllocator {
+    unsafe fn alloc_unchecked(&self, layout: Layout) -> *mut u8 {
+        match self.heap.lock().allocate(layout) {
+            Some(ptr) => {
+                let used = HEAP_USED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+                HEAP_HIGH_WATER_MARK.fetch_max(used, Ordering::Relaxed);
+                ptr.as_ptr()
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc_unchecked(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout);
+        HEAP_USED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "alloc-hardening")]
+        { hardening::alloc(layout, |layout| self.alloc_unchecked(layout)) }
+        #[cfg(not(feature = "alloc-hardening"))]
+        { self.alloc_unchecked(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "alloc-hardening")]
+        { hardening::dealloc(ptr, layout, |ptr, layout| self.dealloc_unchecked(ptr, layout)) }
+        #[cfg(not(feature = "alloc-hardening"))]
+        { self.dealloc_unchecked(ptr, layout) }
+    }
+}
+
+/// Tries to grow the heap by at least `min_size` bytes. Growth happens in steps of at
+/// least the current heap size, so the amount of growth steps stays small.
+///
+/// This only uses `try_lock` on the mapper and frame allocator, since the allocation
+/// might come from code that is holding one of them. Such code can `reserve` heap space
+/// up front instead.
+fn grow_heap(heap: &mut Heap, min_size: usize) -> Result<(), ()> {
+    let mut mapper = crate::memory::MAPPER.try_lock().ok_or(())?;
+    let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.try_lock().ok_or(())?;
+    grow_heap_with(heap, min_size, mapper.as_mut().ok_or(())?, frame_allocator.as_mut().ok_or(())?)
+}
+
+/// Grows the heap until at least `bytes` of it are free, with the mapper and frame allocator
+/// the caller is holding. Allocations made while holding both locks can't grow the heap, so
+/// the caller reserves what they need before making them.
+pub fn reserve(
+    bytes: usize,
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), ()> {
+    let mut heap = ALLOCATOR.heap.lock();
+    let free = heap.large.free();
+    if free >= bytes {
+        return Ok(());
+    }
+    grow_heap_with(&mut heap.large, bytes - free, mapper, frame_allocator)
+}
+
+fn grow_heap_with(
+    heap: &mut Heap,
+    min_size: usize,
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), ()> {
+    let current = HEAP_MAPPED.load(Ordering::SeqCst);
+    let limit = HEAP_LIMIT.load(Ordering::Relaxed);
+    let wanted = align_up(core::cmp::max(min_size, current), Page::<Size4KiB>::SIZE as usize);
+    let size = core::cmp::min(wanted, limit.saturating_sub(current));
+    if size < min_size || size == 0 {
+        return Err(());
+    }
+
+    // Large growth steps get mapped with 2 MiB pages where possible
+    let start = VirtAddr::new((HEAP_START + current) as u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | crate::memory::protection::no_execute();
+    crate::memory::mapping::alloc_and_map_range(start, size as u64, flags, mapper, frame_allocator)
+        .map_err(|_| ())?;
+
+    unsafe { heap.extend(size); }
+    HEAP_MAPPED.store(current + size, Ordering::SeqCst);
+    Ok(())
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,353 @@
+//! Allocation tracking for finding out who uses the heap, enabled by the `alloc-tracking`
+//! feature. Every live allocation is recorded with its size, the return addresses of its
+//! callers and the thread that made it.
+//!
+//! The callers start at the first frame outside the allocator and liballoc, so allocations are
+//! grouped by the code that made them and not by the `Box` or `Vec` function they went through.
+//! Telling those frames apart needs the kernel symbol table, without it the callers start right
+//! at the allocator.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::SlabAllocator;
+use crate::backtrace::{self, symbols};
+use crate::println;
+
+/// Amount of return addresses recorded per allocation, starting at the first caller outside the
+/// allocator
+pub const CALLER_DEPTH: usize = 4;
+/// Most allocator frames skipped before the callers are recorded anyway
+const MAX_SKIPPED_FRAMES: usize = 16;
+/// Symbol prefixes of the allocator, liballoc and the allocation shims of the compiler
+const ALLOCATOR_SYMBOLS: [&str; 6] = [
+    "__rust_", "__rg_", "alloc::", "<alloc::", "kernel::allocator::", "<kernel::allocator::",
+];
+/// Amount of live allocations that can be tracked at the same time
+const TRACKED_CAPACITY: usize = 4096;
+
+static TABLE: Mutex<AllocationTable> = Mutex::new(AllocationTable::new());
+static NEXT_ALLOCATION_ID: AtomicU64 = AtomicU64::new(1);
+/// Allocations that were not recorded because the table was full
+static DROPPED_RECORDS: AtomicUsize = AtomicUsize::new(0);
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Records
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationRecord {
+    /// Increases with every allocation, so records of different snapshots can be matched up
+    pub id: u64,
+    pub ptr: usize,
+    pub size: usize,
+    /// Return addresses of the callers, zero where the stack walk ended early
+    pub callers: [u64; CALLER_DEPTH],
+    /// The thread that made the allocation, `None` if it happened before the scheduler existed
+    pub thread: Option<u64>,
+}
+
+#[derive(Clone, Copy)]
+enum Slot {
+    Empty,
+    Used(AllocationRecord),
+}
+
+/// Open addressing hash table of live allocations, keyed by pointer. It has a fixed size,
+/// because it can't allocate from the heap it is tracking.
+///
+/// Removing a record moves the records after it back into the gap instead of leaving a
+/// tombstone, so a long running kernel doesn't fill the table with tombstones until every
+/// lookup scans all of it.
+struct AllocationTable {
+    slots: [Slot; TRACKED_CAPACITY],
+    len: usize,
+}
+
+impl AllocationTable {
+    const fn new() -> Self {
+        Self {
+            slots: [Slot::Empty; TRACKED_CAPACITY],
+            len: 0,
+        }
+    }
+
+    fn start_index(ptr: usize) -> usize {
+        // Allocations are at least 8 byte aligned, so the low bits carry no information
+        (ptr >> 3).wrapping_mul(0x9E37_79B9_7F4A_7C15) % TRACKED_CAPACITY
+    }
+
+    fn insert(&mut self, record: AllocationRecord) -> bool {
+        let start = Self::start_index(record.ptr);
+        for i in 0..TRACKED_CAPACITY {
+            let slot = &mut self.slots[(start + i) % TRACKED_CAPACITY];
+            if let Slot::Empty = slot {
+                *slot = Slot::Used(record);
+                self.len += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn remove(&mut self, ptr: usize) -> Option<AllocationRecord> {
+        let start = Self::start_index(ptr);
+        for i in 0..TRACKED_CAPACITY {
+            let slot = &mut self.slots[(start + i) % TRACKED_CAPACITY];
+            match *slot {
+                Slot::Empty => return None,
+                Slot::Used(record) if record.ptr == ptr => {
+                    self.close_gap((start + i) % TRACKED_CAPACITY);
+                    self.len -= 1;
+                    return Some(record);
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Empties the slot at `gap` and moves records back into it that could no longer be found
+    /// across the empty slot otherwise (backward shift deletion for linear probing).
+    fn close_gap(&mut self, mut gap: usize) {
+        self.slots[gap] = Slot::Empty;
+        let mut index = gap;
+        for _ in 1..TRACKED_CAPACITY {
+            index = (index + 1) % TRACKED_CAPACITY;
+            let record = match self.slots[index] {
+                Slot::Empty => return,
+                Slot::Used(record) => record,
+            };
+            // The record can stay if its start index lies cyclically in (gap, index]
+            let start = Self::start_index(record.ptr);
+            let reachable = if gap <= index {
+                gap < start && start <= index
+            } else {
+                gap < start || start <= index
+            };
+            if !reachable {
+                self.slots[gap] = Slot::Used(record);
+                self.slots[index] = Slot::Empty;
+                gap = index;
+            }
+        }
+    }
+
+    fn get(&self, ptr: usize) -> Option<AllocationRecord> {
+        let start = Self::start_index(ptr);
+        for i in 0..TRACKED_CAPACITY {
+            match self.slots[(start + i) % TRACKED_CAPACITY] {
+                Slot::Empty => return None,
+                Slot::Used(record) if record.ptr == ptr => return Some(record),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn records(&self) -> impl Iterator<Item = &AllocationRecord> {
+        self.slots.iter().filter_map(|slot| match slot {
+            Slot::Used(record) => Some(record),
+            _ => None,
+        })
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Tracking allocator
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Wraps the slab allocator and records every allocation it hands out.
+pub struct TrackingAllocator {
+    inner: &'static SlabAllocator,
+}
+
+impl TrackingAllocator {
+    pub const fn new(inner: &'static SlabAllocator) -> Self {
+        Self { inner }
+    }
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            let record = AllocationRecord {
+                id: NEXT_ALLOCATION_ID.fetch_add(1, Ordering::Relaxed),
+                ptr: ptr as usize,
+                size: layout.size(),
+                callers: caller_addresses(),
+                thread: crate::threading::current_thread_id_unlocked(),
+            };
+            if !TABLE.lock().insert(record) {
+                DROPPED_RECORDS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // The record stays around during the free, so corruption reports can show its callers
+        self.inner.dealloc(ptr, layout);
+        TABLE.lock().remove(ptr as usize);
+    }
+}
+
+/// Returns the record of the live allocation at `ptr`.
+pub fn find(ptr: usize) -> Option<AllocationRecord> {
+    TABLE.lock().get(ptr)
+}
+
+/// Walks the frame pointer chain and returns the return addresses of the innermost callers
+/// outside the allocator.
+#[inline(always)]
+fn caller_addresses() -> [u64; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+    let mut skipped = 0;
+    let frames = unsafe { backtrace::walk(backtrace::frame_pointer()) }
+        .skip_while(|&return_address| {
+            skipped += 1;
+            skipped <= MAX_SKIPPED_FRAMES && is_allocator_frame(return_address)
+        });
+    for (caller, return_address) in callers.iter_mut().zip(frames) {
+        *caller = return_address;
+    }
+    callers
+}
+
+/// Checks if a return address lies in the allocator or liballoc. Generic liballoc functions are
+/// instantiated in the kernel, but their symbols keep the `alloc::` path.
+fn is_allocator_frame(return_address: u64) -> bool {
+    // The return address follows the call, which can be the last instruction of the function
+    match symbols::resolve(return_address - 1) {
+        Some(symbol) => {
+            ALLOCATOR_SYMBOLS.iter().any(|prefix| symbol.name.starts_with(prefix))
+                || symbol.name.contains(" as alloc::")
+        }
+        None => false,
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Snapshots
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Live allocations at one point in time, sorted by allocation id.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    records: Vec<AllocationRecord>,
+    /// Id the next allocation after the snapshot gets
+    taken_at: u64,
+}
+
+/// Takes a snapshot of the live allocations. The buffer of the snapshot itself is left out.
+pub fn snapshot() -> Snapshot {
+    // The buffer has to be allocated before taking the table lock, the allocation needs it too
+    let capacity = TABLE.lock().len + 16;
+    let mut records = Vec::with_capacity(capacity);
+    let buffer = records.as_ptr() as usize;
+
+    let table = TABLE.lock();
+    let taken_at = NEXT_ALLOCATION_ID.load(Ordering::Relaxed);
+    records.extend(
+        table.records()
+            .filter(|record| record.ptr != buffer)
+            .take(capacity)
+            .copied(),
+    );
+    drop(table);
+
+    records.sort_unstable_by_key(|record: &AllocationRecord| record.id);
+    Snapshot { records, taken_at }
+}
+
+/// Prints the live allocations grouped by call site.
+pub fn dump() {
+    snapshot().dump();
+}
+
+/// Amount of allocations that were not recorded because too many allocations were live.
+pub fn dropped_records() -> usize {
+    DROPPED_RECORDS.load(Ordering::Relaxed)
+}
+
+/// Live allocations that share the same callers.
+#[derive(Debug, Clone, Copy)]
+pub struct CallSite {
+    pub callers: [u64; CALLER_DEPTH],
+    pub allocations: usize,
+    pub bytes: usize,
+}
+
+impl Snapshot {
+    pub fn records(&self) -> &[AllocationRecord] {
+        &self.records
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.records.iter().map(|record| record.size).sum()
+    }
+
+    /// Returns the allocations in this snapshot that were made after `earlier` was taken. If
+    /// `earlier` was taken before some work and this snapshot after it, these are the
+    /// allocations the work leaked.
+    pub fn allocated_since(&self, earlier: &Snapshot) -> Snapshot {
+        let records = self.records
+            .iter()
+            .filter(|record| record.id >= earlier.taken_at)
+            .copied()
+            .collect();
+        Snapshot { records, taken_at: self.taken_at }
+    }
+
+    /// Groups the allocations by their callers, largest amount of bytes first.
+    pub fn by_call_site(&self) -> Vec<CallSite> {
+        let mut call_sites: Vec<CallSite> = Vec::new();
+        for record in &self.records {
+            match call_sites.iter_mut().find(|site| site.callers == record.callers) {
+                Some(site) => {
+                    site.allocations += 1;
+                    site.bytes += record.size;
+                }
+                None => call_sites.push(CallSite {
+                    callers: record.callers,
+                    allocations: 1,
+                    bytes: record.size,
+                }),
+            }
+        }
+        call_sites.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+        call_sites
+    }
+
+    /// Prints the allocations grouped by call site.
+    pub fn dump(&self) {
+        println!("=====LIVE ALLOCATIONS=====");
+        println!("{} allocations, {} bytes", self.len(), self.bytes());
+        for site in self.by_call_site() {
+            print_call_site(&site);
+        }
+        let dropped = dropped_records();
+        if dropped > 0 {
+            println!("{} allocations were not tracked, the table was full", dropped);
+        }
+        println!("=====++++++++++++++++=====");
+    }
+}
+
+fn print_call_site(site: &CallSite) {
+    crate::print!("{:>8} bytes in {:>5} allocations from", site.bytes, site.allocations);
+    for &caller in site.callers.iter().take_while(|&&caller| caller != 0) {
+        crate::print!(" {:#x}", caller);
+    }
+    println!();
+}
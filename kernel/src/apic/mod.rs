@@ -0,0 +1,330 @@
+use cpuio::{inb, outb};
+
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+use crate::memory::Mmio;
+
+pub mod registers;
+
+use registers::{
+    ioapic_register,
+    DeliveryMode,
+    DestinationMode,
+    ErrorStatus,
+    InterruptCommand,
+    LocalApicRegister,
+    Lvt,
+    LvtEntry,
+    RedirectionEntry,
+    SpuriousInterruptVector,
+    TimerDivide,
+    TimerMode,
+};
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Local APIC
+///////////////////////////////////////////////////////////////////////////////////////////////////
+const APIC_ADDRESS: u64 = 0xFEE00000; //TODO: Get this from ACPI table although it shouldn't change
+const APIC_SIZE: u64 = 0x400;
+
+/// Vector the local APIC raises for spurious interrupts
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// One bit per vector that the IOAPIC or a local vector table entry delivers as a fixed
+/// interrupt. Routes are only ever added, a vector that lost its route stays marked.
+static ROUTED_VECTORS: [AtomicU64; 4] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+fn mark_routed(vector: u8) {
+    ROUTED_VECTORS[usize::from(vector / 64)].fetch_or(1 << (vector % 64), Ordering::Relaxed);
+}
+
+/// Returns whether interrupts on `vector` come through the local APIC, which sets their
+/// in-service bit and expects an EOI. Software interrupts on other vectors do neither.
+pub fn is_routed(vector: u8) -> bool {
+    ROUTED_VECTORS[usize::from(vector / 64)].load(Ordering::Relaxed) & 1 << (vector % 64) != 0
+}
+
+/// The memory mapped registers of the local APIC.
+pub struct LocalApic {
+    mmio: Mmio,
+}
+
+impl LocalApic {
+    pub fn read(&self, register: LocalApicRegister) -> u32 {
+        self.mmio.read_u32(register.offset())
+    }
+
+    pub fn write(&self, register: LocalApicRegister, value: u32) {
+        self.mmio.write_u32(register.offset(), value);
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(LocalApicRegister::Id) >> 24) as u8
+    }
+
+    pub fn spurious_interrupt_vector(&self) -> SpuriousInterruptVector {
+        SpuriousInterruptVector::from_raw(self.read(LocalApicRegister::SpuriousInterruptVector))
+    }
+
+    pub fn set_spurious_interrupt_vector(&self, value: SpuriousInterruptVector) {
+        self.write(LocalApicRegister::SpuriousInterruptVector, value.raw());
+    }
+
+    pub fn lvt(&self, lvt: Lvt) -> LvtEntry {
+        LvtEntry::from_raw(self.read(lvt.register()))
+    }
+
+    pub fn set_lvt(&self, lvt: Lvt, entry: LvtEntry) {
+        if entry.delivery_mode() == Some(DeliveryMode::Fixed) && entry.vector() >= 32 {
+            mark_routed(entry.vector());
+        }
+        self.write(lvt.register(), entry.raw());
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(LocalApicRegister::EndOfInterrupt, 0);
+    }
+
+    /// Returns whether the CPU accepted an interrupt on `vector` that has not been ended yet.
+    pub fn in_service(&self, vector: u8) -> bool {
+        self.vector_bit(LocalApicRegister::InService, vector)
+    }
+
+    /// Reads the bit of a vector from one of the 256 bit registers, which are spread over
+    /// 8 registers of 32 bits, 16 bytes apart.
+    fn vector_bit(&self, first: LocalApicRegister, vector: u8) -> bool {
+        let offset = first.offset() + u64::from(vector / 32) * 0x10;
+        self.mmio.read_u32(offset) & (1 << (vector % 32)) != 0
+    }
+
+    /// Returns the errors the APIC detected since the last call.
+    pub fn error_status(&self) -> ErrorStatus {
+        // Writing the register latches the current errors into it
+        self.write(LocalApicRegister::ErrorStatus, 0);
+        ErrorStatus::from_raw(self.read(LocalApicRegister::ErrorStatus))
+    }
+
+    pub fn set_timer_divide(&self, divide: TimerDivide) {
+        self.write(LocalApicRegister::TimerDivideConfiguration, divide as u32);
+    }
+
+    pub fn set_timer_initial_count(&self, count: u32) {
+        self.write(LocalApicRegister::TimerInitialCount, count);
+    }
+
+    pub fn timer_current_count(&self) -> u32 {
+        self.read(LocalApicRegister::TimerCurrentCount)
+    }
+
+    /// Sends an inter-processor interrupt and waits until the APIC has sent it.
+    pub fn send_ipi(&self, command: InterruptCommand) {
+        self.write(LocalApicRegister::InterruptCommandHigh, command.high());
+        self.write(LocalApicRegister::InterruptCommandLow, command.low());
+        while self.interrupt_command().delivery_pending() {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    pub fn interrupt_command(&self) -> InterruptCommand {
+        let low = self.read(LocalApicRegister::InterruptCommandLow) as u64;
+        let high = self.read(LocalApicRegister::InterruptCommandHigh) as u64;
+        InterruptCommand::from_raw(high << 32 | low)
+    }
+}
+
+/// Every CPU sees its own local APIC at the same physical address, so one mapping serves all of them
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+
+pub fn local_apic(_apic_id: u8) -> &'static LocalApic {
+    LOCAL_APIC.get_or_init(|| LocalApic {
+        mmio: unsafe { Mmio::map(PhysAddr::new(APIC_ADDRESS), APIC_SIZE, "local APIC") }
+            .expect("Failed to map the local APIC!"),
+    })
+}
+
+/// The PICs are remapped to the top of the vector space, out of the way of the IOAPIC IRQs
+const PIC_MASTER_OFFSET: u8 = 0xE0;
+const PIC_SLAVE_OFFSET: u8 = 0xE8;
+/// Vectors of IRQ 7 and 15, which the PICs raise for spurious interrupts even while masked
+pub const PIC_MASTER_SPURIOUS_VECTOR: u8 = PIC_MASTER_OFFSET + 7;
+pub const PIC_SLAVE_SPURIOUS_VECTOR: u8 = PIC_SLAVE_OFFSET + 7;
+
+pub unsafe fn disable_pic() {
+    // Set ICW1
+    outb(0x11, 0x20);
+    outb(0x11, 0xa0);
+
+    // Set ICW2 (IRQ base offsets)
+    outb(PIC_MASTER_OFFSET, 0x21);
+    outb(PIC_SLAVE_OFFSET, 0xa1);
+
+    // Set ICW3
+    outb(4, 0x21);
+    outb(2, 0xa1);
+
+    // Set ICW4
+    outb(1, 0x21);
+    outb(1, 0xa1);
+
+    // Set OCW1 (interrupt masks)
+    outb(0xff, 0x21);
+    outb(0xff, 0xa1);
+}
+
+/// Returns the in-service registers of the PICs, the slave in the high byte.
+pub unsafe fn pic_in_service() -> u16 {
+    // OCW3: read the ISR on the next read of the command port
+    outb(0x0b, 0x20);
+    outb(0x0b, 0xa0);
+    u16::from(inb(0xa0)) << 8 | u16::from(inb(0x20))
+}
+
+/// Ends an interrupt at the master PIC.
+pub unsafe fn pic_master_send_eoi() {
+    outb(0x20, 0x20);
+}
+
+/// Ends an interrupt at the slave PIC, the master needs its own EOI for the cascade.
+pub unsafe fn pic_slave_send_eoi() {
+    outb(0x20, 0xa0);
+}
+
+pub unsafe fn enable_apic(apic_id: u8) {
+    let apic = local_apic(apic_id);
+    let mut svr = apic.spurious_interrupt_vector();
+    svr.set_vector(SPURIOUS_VECTOR);
+    svr.set_apic_enabled(true);
+    apic.set_spurious_interrupt_vector(svr);
+}
+
+pub unsafe fn apic_send_eoi(apic_id: u8) {
+    local_apic(apic_id).end_of_interrupt();
+}
+
+pub unsafe fn apic_set_timer(apic_id: u8) {
+    trace!("poggers");
+    let apic = local_apic(apic_id);
+
+    apic.set_timer_divide(TimerDivide::By16);
+
+    trace!("hi");
+
+    let mut timer = LvtEntry::from_raw(0);
+    timer.set_vector(crate::interrupts::PIC_OFFSET); //Timer interrupt
+    timer.set_timer_mode(TimerMode::Periodic);
+    apic.set_lvt(Lvt::Timer, timer);
+    apic.set_timer_initial_count(0); //Reset timer to -1
+    crate::hardware::rtc::sleep(0.01); //Sleep for 10ms
+    timer.set_masked(true);
+    apic.set_lvt(Lvt::Timer, timer); //Stop the timer
+
+    let ticks = apic.timer_current_count();
+
+    trace!("apic timer ticks in 10ms");
+    trace!("{}", ticks);
+
+    // timer.set_masked(false);
+    // apic.set_lvt(Lvt::Timer, timer);
+    // apic.set_timer_initial_count(ticks);
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// IOAPIC
+///////////////////////////////////////////////////////////////////////////////////////////////////
+const IOAPIC_SIZE: u64 = 0x20;
+
+/// The registers of an IOAPIC, which are accessed indirectly through an index and a data register.
+pub struct IoApic {
+    mmio: Mmio,
+}
+
+impl IoApic {
+    pub fn read(&mut self, index: u32) -> u32 {
+        // Write the index to the index register
+        self.mmio.write_u32(ioapic_register::INDEX, index);
+        // Read the value from the data register
+        self.mmio.read_u32(ioapic_register::DATA)
+    }
+
+    pub fn write(&mut self, index: u32, value: u32) {
+        // Write the index to the index register
+        self.mmio.write_u32(ioapic_register::INDEX, index);
+        // Write the value to the data register
+        self.mmio.write_u32(ioapic_register::DATA, value);
+    }
+
+    pub fn id(&mut self) -> u8 {
+        (self.read(ioapic_register::ID) >> 24 & 0xF) as u8
+    }
+
+    /// Amount of interrupt pins, and with that redirection entries, of this IOAPIC.
+    pub fn redirection_entry_count(&mut self) -> u8 {
+        (self.read(ioapic_register::VERSION) >> 16 & 0xFF) as u8 + 1
+    }
+
+    pub fn redirection_entry(&mut self, irq: u8) -> RedirectionEntry {
+        let index = self.redirection_index(irq);
+        let low = self.read(index) as u64;
+        let high = self.read(index + 1) as u64;
+        RedirectionEntry::from_raw(high << 32 | low)
+    }
+
+    /// Writes a redirection entry. The entry is masked while its halves are written, so the
+    /// pin never fires with a half updated entry.
+    pub fn set_redirection_entry(&mut self, irq: u8, entry: RedirectionEntry) {
+        let index = self.redirection_index(irq);
+        let mut masked = self.redirection_entry(irq);
+        masked.set_masked(true);
+        self.write(index, masked.low());
+        self.write(index + 1, entry.high());
+        self.write(index, entry.low());
+    }
+
+    fn redirection_index(&mut self, irq: u8) -> u32 {
+        assert!(irq < self.redirection_entry_count(), "IOAPIC has no IRQ {}", irq);
+        ioapic_register::REDIRECTION_TABLE + irq as u32 * 2
+    }
+}
+
+lazy_static! {
+    /// The lock also keeps the index and data register accesses of a read or write together
+    static ref IOAPIC: spin::Mutex<Option<IoApic>> = spin::Mutex::new(None);
+}
+
+pub fn update_ioapic_addr(addr: u64) {
+    let mmio = unsafe { Mmio::map(PhysAddr::new(addr), IOAPIC_SIZE, "IOAPIC") }.expect("Failed to map the IOAPIC!");
+    *IOAPIC.lock() = Some(IoApic { mmio });
+}
+
+/// Runs `f` with exclusive access to the IOAPIC.
+pub fn with_ioapic<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut IoApic) -> T,
+{
+    let mut ioapic = IOAPIC.lock();
+    f(ioapic.as_mut().expect("IOAPIC address unknown"))
+}
+
+pub unsafe fn ioapic_read(index: u32) -> u32 {
+    with_ioapic(|ioapic| ioapic.read(index))
+}
+
+pub unsafe fn ioapic_write(index: u32, value: u32) {
+    with_ioapic(|ioapic| ioapic.write(index, value))
+}
+
+/// Routes `irq` to `vector` on the local APIC with the given ID, and unmasks it.
+pub unsafe fn ioapic_set_irq(irq: u8, apic_id: u8, vector: u8) {
+    with_ioapic(|ioapic| {
+        let mut entry = ioapic.redirection_entry(irq);
+        entry.set_destination(apic_id);
+        entry.set_destination_mode(DestinationMode::Physical);
+        entry.set_delivery_mode(DeliveryMode::Fixed);
+        entry.set_vector(vector);
+        entry.set_masked(false);
+        ioapic.set_redirection_entry(irq, entry);
+        mark_routed(vector);
+    })
+}
@@ -0,0 +1,631 @@
+use core::fmt;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Register offsets
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Registers of the local APIC, as offsets into its MMIO page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum LocalApicRegister {
+    Id = 0x20,
+    Version = 0x30,
+    TaskPriority = 0x80,
+    EndOfInterrupt = 0xB0,
+    SpuriousInterruptVector = 0xF0,
+    /// First of the 8 in-service registers, each covering 32 vectors
+    InService = 0x100,
+    /// First of the 8 trigger mode registers, each covering 32 vectors
+    TriggerMode = 0x180,
+    /// First of the 8 interrupt request registers, each covering 32 vectors
+    InterruptRequest = 0x200,
+    ErrorStatus = 0x280,
+    InterruptCommandLow = 0x300,
+    InterruptCommandHigh = 0x310,
+    LvtTimer = 0x320,
+    LvtThermalSensor = 0x330,
+    LvtPerformanceCounter = 0x340,
+    LvtLint0 = 0x350,
+    LvtLint1 = 0x360,
+    LvtError = 0x370,
+    TimerInitialCount = 0x380,
+    TimerCurrentCount = 0x390,
+    TimerDivideConfiguration = 0x3E0,
+}
+
+impl LocalApicRegister {
+    pub fn offset(self) -> u64 {
+        self as u64
+    }
+}
+
+/// The local vector table entries, which route local interrupt sources to a vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lvt {
+    Timer,
+    ThermalSensor,
+    PerformanceCounter,
+    Lint0,
+    Lint1,
+    Error,
+}
+
+impl Lvt {
+    pub fn register(self) -> LocalApicRegister {
+        match self {
+            Lvt::Timer => LocalApicRegister::LvtTimer,
+            Lvt::ThermalSensor => LocalApicRegister::LvtThermalSensor,
+            Lvt::PerformanceCounter => LocalApicRegister::LvtPerformanceCounter,
+            Lvt::Lint0 => LocalApicRegister::LvtLint0,
+            Lvt::Lint1 => LocalApicRegister::LvtLint1,
+            Lvt::Error => LocalApicRegister::LvtError,
+        }
+    }
+}
+
+/// Indirect registers of the IOAPIC, selected through its index register.
+pub mod ioapic_register {
+    pub const INDEX: u64 = 0x00;
+    pub const DATA: u64 = 0x10;
+
+    pub const ID: u32 = 0x00;
+    pub const VERSION: u32 = 0x01;
+    /// Low half of the first redirection entry, every entry takes two registers
+    pub const REDIRECTION_TABLE: u32 = 0x10;
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Field values
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DeliveryMode {
+    Fixed = 0b000,
+    LowestPriority = 0b001,
+    Smi = 0b010,
+    Nmi = 0b100,
+    Init = 0b101,
+    StartUp = 0b110,
+    ExtInt = 0b111,
+}
+
+impl DeliveryMode {
+    /// Returns `None` for the reserved value, which a register can still hold if the firmware
+    /// or a buggy write put it there.
+    fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0b000 => Some(DeliveryMode::Fixed),
+            0b001 => Some(DeliveryMode::LowestPriority),
+            0b010 => Some(DeliveryMode::Smi),
+            0b100 => Some(DeliveryMode::Nmi),
+            0b101 => Some(DeliveryMode::Init),
+            0b110 => Some(DeliveryMode::StartUp),
+            0b111 => Some(DeliveryMode::ExtInt),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DestinationMode {
+    Physical,
+    Logical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinPolarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TimerMode {
+    OneShot = 0b00,
+    Periodic = 0b01,
+    TscDeadline = 0b10,
+}
+
+/// Values of the timer divide configuration register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TimerDivide {
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DestinationShorthand {
+    None = 0b00,
+    ToSelf = 0b01,
+    AllIncludingSelf = 0b10,
+    AllExcludingSelf = 0b11,
+}
+
+/// Vectors below this one are reserved, the APIC reports an illegal vector error for them
+pub const FIRST_VALID_VECTOR: u8 = 0x10;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Registers
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Spurious interrupt vector register, which also holds the software enable bit of the APIC.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SpuriousInterruptVector(u32);
+
+impl SpuriousInterruptVector {
+    pub fn from_raw(raw: u32) -> Self {
+        Self(raw)
+    }
+
+    pub fn raw(self) -> u32 {
+        self.0
+    }
+
+    pub fn vector(self) -> u8 {
+        get_bits(self.0 as u64, 0, 8) as u8
+    }
+
+    /// The low 4 bits of the spurious vector are hardwired to 1 on older APICs, so it should
+    /// be of the form 0xXF.
+    pub fn set_vector(&mut self, vector: u8) {
+        self.0 = set_bits(self.0 as u64, 0, 8, vector as u64) as u32;
+    }
+
+    pub fn apic_enabled(self) -> bool {
+        get_bit(self.0 as u64, 8)
+    }
+
+    pub fn set_apic_enabled(&mut self, enabled: bool) {
+        self.0 = set_bit(self.0 as u64, 8, enabled) as u32;
+    }
+}
+
+impl fmt::Debug for SpuriousInterruptVector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SpuriousInterruptVector")
+            .field("vector", &self.vector())
+            .field("apic_enabled", &self.apic_enabled())
+            .finish()
+    }
+}
+
+/// An entry of the local vector table. Not every field applies to every entry: the timer mode
+/// only exists in the timer entry, and polarity and trigger mode only in the LINT entries.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct LvtEntry(u32);
+
+impl LvtEntry {
+    pub fn from_raw(raw: u32) -> Self {
+        Self(raw)
+    }
+
+    pub fn raw(self) -> u32 {
+        self.0
+    }
+
+    pub fn vector(self) -> u8 {
+        get_bits(self.0 as u64, 0, 8) as u8
+    }
+
+    pub fn set_vector(&mut self, vector: u8) {
+        assert!(vector >= FIRST_VALID_VECTOR, "vector {:#x} is reserved", vector);
+        self.0 = set_bits(self.0 as u64, 0, 8, vector as u64) as u32;
+    }
+
+    /// `None` if the field holds the reserved value.
+    pub fn delivery_mode(self) -> Option<DeliveryMode> {
+        DeliveryMode::from_bits(get_bits(self.0 as u64, 8, 3))
+    }
+
+    pub fn set_delivery_mode(&mut self, mode: DeliveryMode) {
+        assert!(
+            matches!(mode, DeliveryMode::Fixed | DeliveryMode::Smi | DeliveryMode::Nmi | DeliveryMode::Init | DeliveryMode::ExtInt),
+            "{:?} is not a valid local vector table delivery mode", mode
+        );
+        self.0 = set_bits(self.0 as u64, 8, 3, mode as u64) as u32;
+    }
+
+    /// True while an interrupt from this source is waiting to be accepted by the CPU
+    pub fn delivery_pending(self) -> bool {
+        get_bit(self.0 as u64, 12)
+    }
+
+    pub fn polarity(self) -> PinPolarity {
+        if get_bit(self.0 as u64, 13) { PinPolarity::ActiveLow } else { PinPolarity::ActiveHigh }
+    }
+
+    pub fn set_polarity(&mut self, polarity: PinPolarity) {
+        self.0 = set_bit(self.0 as u64, 13, polarity == PinPolarity::ActiveLow) as u32;
+    }
+
+    pub fn trigger_mode(self) -> TriggerMode {
+        if get_bit(self.0 as u64, 15) { TriggerMode::Level } else { TriggerMode::Edge }
+    }
+
+    pub fn set_trigger_mode(&mut self, trigger_mode: TriggerMode) {
+        self.0 = set_bit(self.0 as u64, 15, trigger_mode == TriggerMode::Level) as u32;
+    }
+
+    pub fn masked(self) -> bool {
+        get_bit(self.0 as u64, 16)
+    }
+
+    pub fn set_masked(&mut self, masked: bool) {
+        self.0 = set_bit(self.0 as u64, 16, masked) as u32;
+    }
+
+    /// `None` if the field holds the reserved value.
+    pub fn timer_mode(self) -> Option<TimerMode> {
+        match get_bits(self.0 as u64, 17, 2) {
+            0b00 => Some(TimerMode::OneShot),
+            0b01 => Some(TimerMode::Periodic),
+            0b10 => Some(TimerMode::TscDeadline),
+            _ => None,
+        }
+    }
+
+    pub fn set_timer_mode(&mut self, mode: TimerMode) {
+        self.0 = set_bits(self.0 as u64, 17, 2, mode as u64) as u32;
+    }
+}
+
+impl fmt::Debug for LvtEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LvtEntry")
+            .field("vector", &self.vector())
+            .field("delivery_mode", &get_bits(self.0 as u64, 8, 3))
+            .field("delivery_pending", &self.delivery_pending())
+            .field("masked", &self.masked())
+            .field("raw", &format_args!("{:#x}", self.0))
+            .finish()
+    }
+}
+
+/// Error status register. It has to be written before reading to latch the errors that
+/// occurred since the last write.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ErrorStatus(u32);
+
+impl ErrorStatus {
+    const NAMES: [&'static str; 8] = [
+        "send checksum error",
+        "receive checksum error",
+        "send accept error",
+        "receive accept error",
+        "redirectable IPI",
+        "send illegal vector",
+        "received illegal vector",
+        "illegal register address",
+    ];
+
+    pub fn from_raw(raw: u32) -> Self {
+        Self(raw)
+    }
+
+    pub fn raw(self) -> u32 {
+        self.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 & 0xFF == 0
+    }
+
+    pub fn send_illegal_vector(self) -> bool {
+        get_bit(self.0 as u64, 5)
+    }
+
+    pub fn received_illegal_vector(self) -> bool {
+        get_bit(self.0 as u64, 6)
+    }
+
+    pub fn illegal_register_address(self) -> bool {
+        get_bit(self.0 as u64, 7)
+    }
+
+    /// Returns the names of the errors that are set.
+    pub fn errors(self) -> impl Iterator<Item = &'static str> {
+        (0..Self::NAMES.len())
+            .filter(move |&bit| get_bit(self.0 as u64, bit as u8))
+            .map(|bit| Self::NAMES[bit])
+    }
+}
+
+impl fmt::Debug for ErrorStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.errors()).finish()
+    }
+}
+
+/// Interrupt command register, used to send inter-processor interrupts. The high half has to
+/// be written first, writing the low half sends the interrupt.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct InterruptCommand(u64);
+
+impl InterruptCommand {
+    pub fn new(vector: u8, delivery_mode: DeliveryMode, destination: u8) -> Self {
+        let mut command = Self(0);
+        command.set_vector(vector);
+        command.set_delivery_mode(delivery_mode);
+        command.set_destination(destination);
+        command.set_level_assert(true);
+        command
+    }
+
+    pub fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
+
+    pub fn raw(self) -> u64 {
+        self.0
+    }
+
+    pub fn low(self) -> u32 {
+        self.0 as u32
+    }
+
+    pub fn high(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    pub fn vector(self) -> u8 {
+        get_bits(self.0, 0, 8) as u8
+    }
+
+    /// Start-up IPIs use the vector as the page number of the start-up code, so any value is
+    /// allowed here.
+    pub fn set_vector(&mut self, vector: u8) {
+        self.0 = set_bits(self.0, 0, 8, vector as u64);
+    }
+
+    /// `None` if the field holds the reserved value.
+    pub fn delivery_mode(self) -> Option<DeliveryMode> {
+        DeliveryMode::from_bits(get_bits(self.0, 8, 3))
+    }
+
+    pub fn set_delivery_mode(&mut self, mode: DeliveryMode) {
+        assert!(mode != DeliveryMode::ExtInt, "ExtInt can't be sent as an IPI");
+        self.0 = set_bits(self.0, 8, 3, mode as u64);
+    }
+
+    pub fn destination_mode(self) -> DestinationMode {
+        if get_bit(self.0, 11) { DestinationMode::Logical } else { DestinationMode::Physical }
+    }
+
+    pub fn set_destination_mode(&mut self, mode: DestinationMode) {
+        self.0 = set_bit(self.0, 11, mode == DestinationMode::Logical);
+    }
+
+    /// True until the local APIC has sent the interrupt
+    pub fn delivery_pending(self) -> bool {
+        get_bit(self.0, 12)
+    }
+
+    pub fn set_level_assert(&mut self, assert: bool) {
+        self.0 = set_bit(self.0, 14, assert);
+    }
+
+    pub fn trigger_mode(self) -> TriggerMode {
+        if get_bit(self.0, 15) { TriggerMode::Level } else { TriggerMode::Edge }
+    }
+
+    pub fn set_trigger_mode(&mut self, trigger_mode: TriggerMode) {
+        self.0 = set_bit(self.0, 15, trigger_mode == TriggerMode::Level);
+    }
+
+    pub fn destination_shorthand(self) -> DestinationShorthand {
+        match get_bits(self.0, 18, 2) {
+            0b00 => DestinationShorthand::None,
+            0b01 => DestinationShorthand::ToSelf,
+            0b10 => DestinationShorthand::AllIncludingSelf,
+            _ => DestinationShorthand::AllExcludingSelf,
+        }
+    }
+
+    pub fn set_destination_shorthand(&mut self, shorthand: DestinationShorthand) {
+        self.0 = set_bits(self.0, 18, 2, shorthand as u64);
+    }
+
+    pub fn destination(self) -> u8 {
+        get_bits(self.0, 56, 8) as u8
+    }
+
+    pub fn set_destination(&mut self, destination: u8) {
+        self.0 = set_bits(self.0, 56, 8, destination as u64);
+    }
+}
+
+impl fmt::Debug for InterruptCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InterruptCommand")
+            .field("vector", &self.vector())
+            .field("delivery_mode", &self.delivery_mode())
+            .field("destination_mode", &self.destination_mode())
+            .field("delivery_pending", &self.delivery_pending())
+            .field("destination_shorthand", &self.destination_shorthand())
+            .field("destination", &self.destination())
+            .finish()
+    }
+}
+
+/// An IOAPIC redirection entry, which routes an interrupt pin to a vector on one or more CPUs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry(u64);
+
+impl RedirectionEntry {
+    pub fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
+
+    pub fn raw(self) -> u64 {
+        self.0
+    }
+
+    pub fn low(self) -> u32 {
+        self.0 as u32
+    }
+
+    pub fn high(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    pub fn vector(self) -> u8 {
+        get_bits(self.0, 0, 8) as u8
+    }
+
+    pub fn set_vector(&mut self, vector: u8) {
+        assert!(vector >= FIRST_VALID_VECTOR, "vector {:#x} is reserved", vector);
+        self.0 = set_bits(self.0, 0, 8, vector as u64);
+    }
+
+    /// `None` if the field holds the reserved value.
+    pub fn delivery_mode(self) -> Option<DeliveryMode> {
+        DeliveryMode::from_bits(get_bits(self.0, 8, 3))
+    }
+
+    pub fn set_delivery_mode(&mut self, mode: DeliveryMode) {
+        assert!(mode != DeliveryMode::StartUp, "start-up can't be used as a redirection delivery mode");
+        self.0 = set_bits(self.0, 8, 3, mode as u64);
+    }
+
+    pub fn destination_mode(self) -> DestinationMode {
+        if get_bit(self.0, 11) { DestinationMode::Logical } else { DestinationMode::Physical }
+    }
+
+    pub fn set_destination_mode(&mut self, mode: DestinationMode) {
+        self.0 = set_bit(self.0, 11, mode == DestinationMode::Logical);
+    }
+
+    /// True while the interrupt is waiting to be delivered
+    pub fn delivery_pending(self) -> bool {
+        get_bit(self.0, 12)
+    }
+
+    pub fn polarity(self) -> PinPolarity {
+        if get_bit(self.0, 13) { PinPolarity::ActiveLow } else { PinPolarity::ActiveHigh }
+    }
+
+    pub fn set_polarity(&mut self, polarity: PinPolarity) {
+        self.0 = set_bit(self.0, 13, polarity == PinPolarity::ActiveLow);
+    }
+
+    /// For level triggered interrupts, true from the moment a local APIC accepts the interrupt
+    /// until it sends the EOI
+    pub fn remote_irr(self) -> bool {
+        get_bit(self.0, 14)
+    }
+
+    pub fn trigger_mode(self) -> TriggerMode {
+        if get_bit(self.0, 15) { TriggerMode::Level } else { TriggerMode::Edge }
+    }
+
+    pub fn set_trigger_mode(&mut self, trigger_mode: TriggerMode) {
+        self.0 = set_bit(self.0, 15, trigger_mode == TriggerMode::Level);
+    }
+
+    pub fn masked(self) -> bool {
+        get_bit(self.0, 16)
+    }
+
+    pub fn set_masked(&mut self, masked: bool) {
+        self.0 = set_bit(self.0, 16, masked);
+    }
+
+    /// APIC ID in physical destination mode, a set of processors in logical destination mode
+    pub fn destination(self) -> u8 {
+        get_bits(self.0, 56, 8) as u8
+    }
+
+    pub fn set_destination(&mut self, destination: u8) {
+        self.0 = set_bits(self.0, 56, 8, destination as u64);
+    }
+}
+
+impl fmt::Debug for RedirectionEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RedirectionEntry")
+            .field("vector", &self.vector())
+            .field("delivery_mode", &self.delivery_mode())
+            .field("destination_mode", &self.destination_mode())
+            .field("polarity", &self.polarity())
+            .field("trigger_mode", &self.trigger_mode())
+            .field("masked", &self.masked())
+            .field("destination", &self.destination())
+            .finish()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Utility functions
+///////////////////////////////////////////////////////////////////////////////////////////////////
+fn get_bit(value: u64, bit: u8) -> bool {
+    value & (1 << bit) != 0
+}
+
+fn set_bit(value: u64, bit: u8, set: bool) -> u64 {
+    if set { value | (1 << bit) } else { value & !(1 << bit) }
+}
+
+fn get_bits(value: u64, start: u8, width: u8) -> u64 {
+    (value >> start) & ((1 << width) - 1)
+}
+
+fn set_bits(value: u64, start: u8, width: u8, bits: u64) -> u64 {
+    let mask = ((1 << width) - 1) << start;
+    assert!(bits << start & !mask == 0, "{:#x} does not fit in {} bits", bits, width);
+    (value & !mask) | (bits << start)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[test_case]
+fn test_redirection_entry_fields() {
+    let mut entry = RedirectionEntry::from_raw(1 << 16);
+    entry.set_vector(0x21);
+    entry.set_delivery_mode(DeliveryMode::Fixed);
+    entry.set_destination_mode(DestinationMode::Physical);
+    entry.set_masked(false);
+    entry.set_destination(3);
+    assert_eq!(entry.low(), 0x21);
+    assert_eq!(entry.high(), 3 << 24);
+    assert_eq!(entry.delivery_mode(), Some(DeliveryMode::Fixed));
+}
+
+/// Entries with the reserved delivery mode can still be read and printed
+#[test_case]
+fn test_reserved_delivery_mode() {
+    let entry = RedirectionEntry::from_raw(0b011 << 8 | 0x21);
+    assert_eq!(entry.delivery_mode(), None);
+    // Lib tests have no heap, so the output goes nowhere
+    struct Discard;
+    impl fmt::Write for Discard {
+        fn write_str(&mut self, _: &str) -> fmt::Result {
+            Ok(())
+        }
+    }
+    fmt::write(&mut Discard, format_args!("{:?}", entry)).unwrap();
+}
+
+#[test_case]
+fn test_lvt_timer_entry_fields() {
+    let mut entry = LvtEntry::from_raw(0);
+    entry.set_vector(0x20);
+    entry.set_timer_mode(TimerMode::Periodic);
+    assert_eq!(entry.raw(), 0x20020);
+    entry.set_masked(true);
+    assert_eq!(entry.raw(), 0x30020);
+    assert_eq!(entry.timer_mode(), Some(TimerMode::Periodic));
+    assert_eq!(LvtEntry::from_raw(0b11 << 17).timer_mode(), None);
+}
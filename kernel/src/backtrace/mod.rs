@@ -0,0 +1,155 @@
+//! Stack walking for panics and faults.
+//!
+//! The kernel is built with frame pointers, so every frame starts with the frame pointer of its
+//! caller, followed by the return address into the caller. Return addresses are resolved to
+//! function names with the symbol table in `symbols`.
+
+pub mod symbols;
+
+use x86_64::VirtAddr;
+
+use core::fmt;
+use core::sync::atomic::Ordering;
+
+use crate::{println, serial_println};
+
+/// Most frames a backtrace holds
+pub const MAX_FRAMES: usize = 32;
+/// Frames further apart than this are not part of the same stack, so the walk stops there
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+
+/// Returns the frame pointer of the function this is inlined into.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let frame: u64;
+    unsafe { llvm_asm!("mov $0, rbp" : "=r"(frame) ::: "intel"); }
+    frame
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Walking
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Iterates over the return addresses of a frame pointer chain, innermost first.
+pub struct Frames {
+    frame: u64,
+}
+
+/// Walks the frame pointer chain starting at the frame `frame_pointer` points to.
+///
+/// This function is unsafe because the frame pointer must come from a stack that follows the
+/// frame pointer convention. Frames that are unmapped or out of order end the walk.
+pub unsafe fn walk(frame_pointer: u64) -> Frames {
+    Frames { frame: frame_pointer }
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let frame = self.frame;
+        if frame == 0 || frame % 8 != 0 || !is_mapped(frame) || !is_mapped(frame + 8) {
+            return None;
+        }
+        // A frame holds the frame pointer of its caller, followed by the return address
+        let (next_frame, return_address) = unsafe {
+            let frame = frame as *const u64;
+            (*frame, *frame.add(1))
+        };
+        // Callers live higher up on the same stack, anything else means the chain is broken
+        self.frame = if next_frame <= frame || next_frame - frame > MAX_FRAME_SIZE {
+            0
+        } else {
+            next_frame
+        };
+        if return_address == 0 {
+            return None;
+        }
+        Some(return_address)
+    }
+}
+
+/// Checks that reading `addr` won't fault. Before the physical memory offset is known the page
+/// tables can't be read, so only the checks on the chain itself protect the walk then.
+fn is_mapped(addr: u64) -> bool {
+    let offset = crate::memory::PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        return true;
+    }
+    let addr = match VirtAddr::try_new(addr) {
+        Ok(addr) => addr,
+        Err(_) => return false,
+    };
+    unsafe { crate::memory::translate_addr(addr, VirtAddr::new(offset)) }.is_some()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Backtraces
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// The addresses of a call stack, innermost first. Capturing one doesn't allocate, so it works
+/// in a panic caused by the allocator.
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    addresses: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Captures the call stack of the function this is inlined into, starting at its caller.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        Self::from_frames(None, frame_pointer())
+    }
+
+    /// Captures the call stack of interrupted code, starting at the instruction it was
+    /// interrupted at.
+    pub fn from_frame(instruction_pointer: u64, frame_pointer: u64) -> Self {
+        Self::from_frames(Some(instruction_pointer), frame_pointer)
+    }
+
+    fn from_frames(first: Option<u64>, frame_pointer: u64) -> Self {
+        let mut backtrace = Self { addresses: [0; MAX_FRAMES], len: 0 };
+        let frames = first.into_iter().chain(unsafe { walk(frame_pointer) });
+        for (slot, address) in backtrace.addresses.iter_mut().zip(frames) {
+            *slot = address;
+            backtrace.len += 1;
+        }
+        backtrace
+    }
+
+    pub fn addresses(&self) -> &[u64] {
+        &self.addresses[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Backtrace:")?;
+        for (index, &address) in self.addresses().iter().enumerate() {
+            write!(f, "\n  #{:<2} {:#018x}", index, address)?;
+            if let Some(symbol) = symbols::resolve(address) {
+                write!(f, " {}+{:#x}", symbol.name, symbol.offset)?;
+            }
+        }
+        if self.len == 0 {
+            write!(f, " <empty>")?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints a backtrace to the screen and the serial port.
+pub fn print(backtrace: &Backtrace) {
+    println!("{}", backtrace);
+    serial_println!("{}", backtrace);
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// The test function was called by the test runner, so there is at least its return address
+#[test_case]
+fn test_capture() {
+    let backtrace = Backtrace::capture();
+    assert!(!backtrace.addresses().is_empty());
+    assert!(backtrace.addresses().iter().all(|&address| address != 0));
+}
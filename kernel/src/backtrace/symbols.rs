@@ -0,0 +1,186 @@
+//! The kernel symbol table.
+//!
+//! The table lives in `KERNEL_SYMBOLS`, which the linker leaves empty. `tools/embed_symbols.py`
+//! fills it in after the kernel is linked, from the ELF symbol table of the kernel binary:
+//!
+//! ```text
+//! | magic | count: u64 | entries: [SymbolEntry; count] | names |
+//! ```
+//!
+//! Entries are sorted by address, and name offsets are relative to the start of the data after
+//! the magic. Without the table backtraces show plain addresses.
+
+use core::mem::size_of;
+
+const MAGIC: [u8; 8] = *b"KSYMTAB\0";
+const CAPACITY: usize = 512 * 1024;
+
+#[repr(C, align(8))]
+pub struct SymbolTableStorage {
+    magic: [u8; 8],
+    data: [u8; CAPACITY],
+}
+
+/// Filled in after linking, see the module docs. It is exported, so the compiler can't assume
+/// it still holds its initial contents.
+#[no_mangle]
+#[used]
+pub static mut KERNEL_SYMBOLS: SymbolTableStorage = SymbolTableStorage { magic: MAGIC, data: [0; CAPACITY] };
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SymbolEntry {
+    address: u64,
+    size: u64,
+    name_offset: u32,
+    name_len: u32,
+}
+
+/// A function an address resolved to.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    /// Start address of the function
+    pub address: u64,
+    /// Offset of the resolved address into the function
+    pub offset: u64,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Lookup
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// The data of a symbol table after the magic. Every access is bounds checked, this runs in
+/// panics and a damaged table must not cause another one.
+#[derive(Clone, Copy)]
+struct SymbolTable<'a> {
+    data: &'a [u8],
+}
+
+/// The embedded table of the kernel, empty if it was never filled in.
+fn kernel_table() -> SymbolTable<'static> {
+    let storage = unsafe { &KERNEL_SYMBOLS };
+    let data: &'static [u8] = if storage.magic == MAGIC { &storage.data } else { &[] };
+    SymbolTable { data }
+}
+
+impl<'a> SymbolTable<'a> {
+    fn read_u64(&self, offset: usize) -> Option<u64> {
+        let bytes = self.data.get(offset..offset.checked_add(8)?)?;
+        let mut value = [0; 8];
+        value.copy_from_slice(bytes);
+        Some(u64::from_le_bytes(value))
+    }
+
+    fn read_u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset.checked_add(4)?)?;
+        let mut value = [0; 4];
+        value.copy_from_slice(bytes);
+        Some(u32::from_le_bytes(value))
+    }
+
+    fn count(&self) -> usize {
+        self.read_u64(0).map_or(0, |count| count as usize)
+    }
+
+    fn entry(&self, index: usize) -> Option<SymbolEntry> {
+        let offset = size_of::<u64>().checked_add(index.checked_mul(size_of::<SymbolEntry>())?)?;
+        Some(SymbolEntry {
+            address: self.read_u64(offset)?,
+            size: self.read_u64(offset + 8)?,
+            name_offset: self.read_u32(offset + 16)?,
+            name_len: self.read_u32(offset + 20)?,
+        })
+    }
+
+    fn name(&self, entry: &SymbolEntry) -> Option<&'a str> {
+        let start = entry.name_offset as usize;
+        let bytes = self.data.get(start..start.checked_add(entry.name_len as usize)?)?;
+        core::str::from_utf8(bytes).ok()
+    }
+
+    fn resolve(&self, address: u64) -> Option<Symbol<'a>> {
+        // Binary search for the last symbol that starts at or before the address
+        let (mut low, mut high) = (0, self.count());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.entry(middle)?.address <= address {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        let entry = self.entry(low.checked_sub(1)?)?;
+        let offset = address - entry.address;
+        if entry.size != 0 && offset >= entry.size {
+            return None;
+        }
+        Some(Symbol { name: self.name(&entry)?, address: entry.address, offset })
+    }
+}
+
+/// Number of symbols in the kernel symbol table, 0 if it was never filled in.
+pub fn count() -> usize {
+    kernel_table().count()
+}
+
+/// Finds the kernel function that contains `address`.
+pub fn resolve(address: u64) -> Option<Symbol<'static>> {
+    kernel_table().resolve(address)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Writes a table of `(address, size, name)` into `buffer`, in the layout of the module docs.
+#[cfg(test)]
+fn build_table<'a>(buffer: &'a mut [u8], symbols: &[(u64, u64, &str)]) -> SymbolTable<'a> {
+    buffer[..8].copy_from_slice(&(symbols.len() as u64).to_le_bytes());
+    let mut name_offset = 8 + symbols.len() * size_of::<SymbolEntry>();
+    for (index, (address, size, name)) in symbols.iter().enumerate() {
+        let entry = 8 + index * size_of::<SymbolEntry>();
+        buffer[entry..entry + 8].copy_from_slice(&address.to_le_bytes());
+        buffer[entry + 8..entry + 16].copy_from_slice(&size.to_le_bytes());
+        buffer[entry + 16..entry + 20].copy_from_slice(&(name_offset as u32).to_le_bytes());
+        buffer[entry + 20..entry + 24].copy_from_slice(&(name.len() as u32).to_le_bytes());
+        buffer[name_offset..name_offset + name.len()].copy_from_slice(name.as_bytes());
+        name_offset += name.len();
+    }
+    SymbolTable { data: buffer }
+}
+
+/// A table that was never filled in resolves nothing
+#[test_case]
+fn test_resolve_empty_table() {
+    let table = SymbolTable { data: &[0; 64] };
+    assert_eq!(table.count(), 0);
+    assert!(table.resolve(0x1000).is_none());
+    assert!(SymbolTable { data: &[] }.resolve(0x1000).is_none());
+}
+
+/// Addresses resolve to the last symbol at or before them, within its size if it has one
+#[test_case]
+fn test_resolve() {
+    let mut buffer = [0; 128];
+    let table = build_table(&mut buffer, &[(0x1000, 0x10, "a"), (0x2000, 0, "bb"), (0x3000, 0x8, "ccc")]);
+    let resolved = |address| table.resolve(address).map(|symbol| (symbol.name, symbol.offset));
+    assert_eq!(resolved(0xFFF), None);
+    assert_eq!(resolved(0x1000), Some(("a", 0)));
+    assert_eq!(resolved(0x100F), Some(("a", 0xF)));
+    assert_eq!(resolved(0x1010), None);
+    // A symbol without a size covers everything up to the next one
+    assert_eq!(resolved(0x2FFF), Some(("bb", 0xFFF)));
+    assert_eq!(resolved(0x3007), Some(("ccc", 7)));
+    assert_eq!(resolved(0x3008), None);
+}
+
+/// Entries pointing outside of the table are ignored instead of panicking
+#[test_case]
+fn test_resolve_damaged_table() {
+    let mut buffer = [0; 64];
+    build_table(&mut buffer, &[(0x1000, 0, "a")]);
+    buffer[8 + 16..8 + 20].copy_from_slice(&1000u32.to_le_bytes());
+    assert!(SymbolTable { data: &buffer }.resolve(0x1000).is_none());
+    // A count larger than the table ends the search at the end of the data
+    buffer[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(SymbolTable { data: &buffer }.resolve(0x1000).is_none());
+}
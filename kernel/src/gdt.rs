@@ -1,30 +1,91 @@
 use x86_64::VirtAddr;
+use x86_64::structures::paging::{mapper::UnmapError, Mapper, Page, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use lazy_static::lazy_static;
 
+use crate::percpu;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+pub const NMI_IST_INDEX: u16 = 2;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 3;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+const IST_STACK_COUNT: usize = 4;
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+/// An interrupt stack with a guard page below it. The guard page is part of the image, so it is
+/// only unmapped once the page tables can be changed, see `add_ist_guard_pages`.
+#[repr(C, align(4096))]
+struct IstStack {
+    guard: [u8; 4096],
+    stack: [u8; IST_STACK_SIZE],
+}
+
+impl IstStack {
+    const fn new() -> Self {
+        Self {
+            guard: [0; 4096],
+            stack: [0; IST_STACK_SIZE],
+        }
+    }
+
+    fn top(&self) -> VirtAddr {
+        VirtAddr::from_ptr(&self.stack) + IST_STACK_SIZE
+    }
+
+    fn guard_page(&self) -> Page {
+        Page::containing_address(VirtAddr::from_ptr(&self.guard))
+    }
+}
+
+static mut IST_STACKS: [IstStack; IST_STACK_COUNT] =
+    [IstStack::new(), IstStack::new(), IstStack::new(), IstStack::new()];
+
+/// Builds the TSS of a CPU, with its interrupt stacks.
+fn create_tss() -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    for (index, stack) in unsafe { IST_STACKS.iter() }.enumerate() {
+        tss.interrupt_stack_table[index] = stack.top();
+    }
+    tss
+}
+
+/// Unmaps the guard pages below the interrupt stacks, so a handler that overflows its stack
+/// page faults instead of overwriting whatever lies below it.
+pub fn add_ist_guard_pages(mapper: &mut impl Mapper<Size4KiB>) {
+    for stack in unsafe { IST_STACKS.iter() } {
+        match mapper.unmap(stack.guard_page()) {
+            // The frame stays part of the kernel image, it is never handed out again
+            Ok((_, flush)) => flush.flush(),
+            Err(UnmapError::PageNotMapped) => {}
+            Err(err) => panic!("failed to unmap IST guard page: {:?}", err),
+        }
+    }
+}
+
+/// Returns the guard page below the interrupt stack with the given IST index.
+pub fn ist_guard_page(index: u16) -> Page {
+    unsafe { IST_STACKS[usize::from(index)].guard_page() }
+}
+
+/// Returns the top of the interrupt stack with the given IST index, where the CPU starts pushing.
+pub fn ist_stack_top(index: u16) -> VirtAddr {
+    unsafe { IST_STACKS[usize::from(index)].top() }
+}
+
+/// Returns true if `addr` lies on the interrupt stack with the given IST index or in its guard
+/// page, which is where the stack pointer ends up when the stack overflows.
+pub fn ist_stack_contains(index: u16, addr: VirtAddr) -> bool {
+    let stack = unsafe { &IST_STACKS[usize::from(index)] };
+    stack.guard_page().start_address() <= addr && addr < stack.top()
 }
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(percpu::this_cpu().tss()));
         (gdt, Selectors { code_selector, tss_selector })
     };
 }
@@ -38,6 +99,8 @@ pub fn init() {
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
 
+    // The GDT refers to the TSS in the per-CPU block, so that has to exist first
+    unsafe { percpu::init_bsp(create_tss()); }
     GDT.0.load();
     unsafe {
         set_cs(GDT.1.code_selector);
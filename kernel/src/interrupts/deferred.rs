@@ -0,0 +1,124 @@
+//! Deferred interrupt work, or bottom halves.
+//!
+//! Interrupt handlers should only do what can't wait, like reading a scancode before the next
+//! one arrives. Everything else, logging in particular, takes locks that the interrupted code
+//! might hold. Handlers schedule that part as a `Work` item instead, and the worker thread runs
+//! it later with interrupts enabled.
+//!
+//! The queue has a fixed capacity and is created in `init`, so scheduling never allocates.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+
+use crate::threading;
+
+/// Number of work items that can be pending at once
+pub const QUEUE_CAPACITY: usize = 256;
+
+static QUEUE: OnceCell<ArrayQueue<Work>> = OnceCell::uninit();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// A function to run outside of interrupt context, with a word of data for it.
+#[derive(Debug, Clone, Copy)]
+pub struct Work {
+    pub name: &'static str,
+    pub func: fn(u64),
+    pub data: u64,
+}
+
+impl Work {
+    pub const fn new(name: &'static str, func: fn(u64), data: u64) -> Self {
+        Self { name, func, data }
+    }
+
+    fn run(self) {
+        (self.func)(self.data)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduleError {
+    /// `init` wasn't called yet
+    Uninitialized,
+    /// The worker fell behind and the queue is full
+    QueueFull,
+}
+
+/// Creates the queue. Needs the heap.
+pub fn init() {
+    QUEUE.try_init_once(|| ArrayQueue::new(QUEUE_CAPACITY))
+        .expect("deferred::init should only be called once");
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Scheduling
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Queues `work` to run in the worker thread. Doesn't block or allocate, so it can be called
+/// from interrupt handlers. Work that doesn't fit is dropped and counted.
+pub fn schedule(work: Work) -> Result<(), ScheduleError> {
+    let result = match QUEUE.try_get() {
+        Ok(queue) => queue.push(work).map_err(|_| ScheduleError::QueueFull),
+        Err(_) => Err(ScheduleError::Uninitialized),
+    };
+    if result.is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+/// Number of work items that were dropped because they couldn't be queued.
+pub fn dropped_count() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+pub fn pending_count() -> usize {
+    QUEUE.try_get().map_or(0, |queue| queue.len())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Running
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Runs the work that is pending right now and returns how many items ran. Work scheduled in
+/// the meantime waits for the next call, so a busy interrupt can't keep the caller here forever.
+///
+/// Must not be called from interrupt context.
+pub fn run_pending() -> usize {
+    debug_assert!(interrupts::are_enabled(), "deferred work must run with interrupts enabled");
+    let queue = match QUEUE.try_get() {
+        Ok(queue) => queue,
+        Err(_) => return 0,
+    };
+    let pending = queue.len();
+    let mut ran = 0;
+    while ran < pending {
+        match queue.pop() {
+            Ok(work) => work.run(),
+            Err(_) => break,
+        }
+        ran += 1;
+    }
+    ran
+}
+
+/// Entry point of the kernel thread that drains the queue. It sleeps until the next interrupt
+/// when there is nothing to do, as that is the only thing that schedules work.
+///
+/// The queue is checked with interrupts disabled, and `sti; hlt` only lets an interrupt in once
+/// the CPU is halted, so work scheduled right after the check still wakes the worker up.
+pub fn worker_thread() -> ! {
+    loop {
+        if run_pending() == 0 {
+            interrupts::disable();
+            if pending_count() == 0 {
+                interrupts::enable_interrupts_and_hlt();
+            } else {
+                interrupts::enable();
+            }
+        }
+        threading::yield_now();
+    }
+}
@@ -0,0 +1,286 @@
+//! Runtime registration of interrupt handlers.
+//!
+//! Every vector from `FIRST_VECTOR` up gets a small stub in the IDT that calls `dispatch` with
+//! its vector number. The dispatcher runs the handlers registered for the vector and sends the
+//! EOI afterwards, so handlers only deal with their device.
+//!
+//! Handlers run with interrupts disabled and the handler table locked, so they must not
+//! register or unregister handlers themselves.
+
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::apic;
+use crate::percpu::{this_cpu, InterruptGs};
+
+/// First vector after the exceptions
+pub const FIRST_VECTOR: u8 = 32;
+/// First vector `allocate_vector` hands out, the ones below belong to the legacy ISA IRQs
+pub const FIRST_DYNAMIC_VECTOR: u8 = super::PIC_OFFSET + 16;
+/// Last vector `allocate_vector` hands out, 0xFF is the spurious vector of the local APIC
+pub const LAST_DYNAMIC_VECTOR: u8 = 0xFE;
+
+const VECTOR_COUNT: usize = 256 - FIRST_VECTOR as usize;
+
+static NEXT_HANDLER_ID: AtomicU64 = AtomicU64::new(0);
+static UNHANDLED: AtomicU64 = AtomicU64::new(0);
+
+/// Tells the dispatcher whether a handler's device raised the interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    NotHandled,
+}
+
+pub enum Handler {
+    /// A function that gets the context pointer it was registered with
+    Fn { handler: fn(context: *mut ()) -> IrqReturn, context: *mut () },
+    Closure(Box<dyn FnMut() -> IrqReturn + Send>),
+}
+
+// The context pointer belongs to the driver that registered it, which has to make sure it can
+// be used from interrupt context on any CPU
+unsafe impl Send for Handler {}
+
+impl Handler {
+    fn call(&mut self) -> IrqReturn {
+        match self {
+            Handler::Fn { handler, context } => handler(*context),
+            Handler::Closure(closure) => closure(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// The vector is an exception or has a fixed handler in the IDT
+    InvalidVector,
+    /// The vector has a handler that does not share it, or the new handler does not want to share
+    Busy,
+}
+
+/// Identifies a registered handler, to unregister it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: u64,
+}
+
+impl HandlerId {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+struct Registration {
+    id: u64,
+    name: &'static str,
+    shared: bool,
+    handler: Handler,
+}
+
+#[derive(Default)]
+struct Vector {
+    /// Handed out by `allocate_vector`, or taken by a handler
+    allocated: bool,
+    /// Has a fixed handler in the IDT that bypasses the dispatcher
+    reserved: bool,
+    handlers: Vec<Registration>,
+}
+
+lazy_static! {
+    static ref VECTORS: spin::Mutex<Vec<Vector>> = {
+        let mut vectors: Vec<Vector> = (0..VECTOR_COUNT).map(|_| Vector::default()).collect();
+        // The local APIC spurious vector is taken from the start, it must never reach a driver
+        let spurious = &mut vectors[index(apic::SPURIOUS_VECTOR).unwrap()];
+        spurious.allocated = true;
+        spurious.reserved = true;
+        spin::Mutex::new(vectors)
+    };
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Registration
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Creates the handler table, this has to happen before the first interrupt is dispatched,
+/// because it allocates.
+pub fn init() {
+    lazy_static::initialize(&VECTORS);
+}
+
+/// Runs `f` on the handler table with interrupts disabled, so an interrupt on this CPU can't
+/// try to take the lock while it is held.
+fn with_vectors<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut Vec<Vector>) -> T,
+{
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut VECTORS.lock()))
+}
+
+fn index(vector: u8) -> Option<usize> {
+    vector.checked_sub(FIRST_VECTOR).map(usize::from)
+}
+
+/// Hands out a vector nothing uses yet.
+pub fn allocate_vector() -> Option<u8> {
+    with_vectors(|vectors| {
+        let vector = (FIRST_DYNAMIC_VECTOR..=LAST_DYNAMIC_VECTOR).find(|&vector| {
+            let state = &vectors[index(vector).unwrap()];
+            !state.allocated && !state.reserved
+        })?;
+        vectors[index(vector).unwrap()].allocated = true;
+        Some(vector)
+    })
+}
+
+/// Gives a vector from `allocate_vector` back. Does nothing while handlers are registered for it.
+pub fn free_vector(vector: u8) {
+    with_vectors(|vectors| {
+        if let Some(state) = index(vector).and_then(|slot| vectors.get_mut(slot)) {
+            if state.handlers.is_empty() {
+                state.allocated = false;
+            }
+        }
+    })
+}
+
+/// Keeps the dispatcher away from a vector that has its own entry in the IDT.
+pub(super) fn reserve_vector(vector: u8) {
+    with_vectors(|vectors| {
+        let state = &mut vectors[index(vector).expect("can't reserve an exception vector")];
+        assert!(state.handlers.is_empty(), "vector {} already has handlers", vector);
+        state.allocated = true;
+        state.reserved = true;
+    })
+}
+
+/// Registers a handler for `vector`. Shared handlers run one after another for every interrupt
+/// on the vector and return whether their device raised it. All handlers of a vector have to
+/// agree on sharing it.
+pub fn register(vector: u8, name: &'static str, shared: bool, handler: Handler) -> Result<HandlerId, RegisterError> {
+    with_vectors(|vectors| {
+        let state = index(vector)
+            .and_then(|slot| vectors.get_mut(slot))
+            .filter(|state| !state.reserved)
+            .ok_or(RegisterError::InvalidVector)?;
+        if state.handlers.iter().any(|registration| !registration.shared || !shared) {
+            return Err(RegisterError::Busy);
+        }
+
+        let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+        state.allocated = true;
+        state.handlers.push(Registration { id, name, shared, handler });
+        Ok(HandlerId { vector, id })
+    })
+}
+
+/// Registers a function that gets `context` passed to it.
+pub fn register_fn(
+    vector: u8,
+    name: &'static str,
+    shared: bool,
+    handler: fn(context: *mut ()) -> IrqReturn,
+    context: *mut (),
+) -> Result<HandlerId, RegisterError> {
+    register(vector, name, shared, Handler::Fn { handler, context })
+}
+
+pub fn register_closure<F>(vector: u8, name: &'static str, shared: bool, handler: F) -> Result<HandlerId, RegisterError>
+where
+    F: FnMut() -> IrqReturn + Send + 'static,
+{
+    register(vector, name, shared, Handler::Closure(Box::new(handler)))
+}
+
+/// Removes a handler. The vector stays allocated, see `free_vector`. Returns false if the
+/// handler was not registered.
+pub fn unregister(id: HandlerId) -> bool {
+    // The handler is dropped after the lock is released, its destructor runs driver code
+    let removed = with_vectors(|vectors| {
+        let state = &mut vectors[index(id.vector).expect("invalid handler id")];
+        let position = state.handlers.iter().position(|registration| registration.id == id.id)?;
+        Some(state.handlers.remove(position))
+    });
+    removed.is_some()
+}
+
+/// Returns the names of the handlers registered for a vector.
+pub fn handler_names(vector: u8) -> Vec<&'static str> {
+    with_vectors(|vectors| match index(vector).and_then(|slot| vectors.get(slot)) {
+        Some(state) => state.handlers.iter().map(|registration| registration.name).collect(),
+        None => Vec::new(),
+    })
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Dispatching
+///////////////////////////////////////////////////////////////////////////////////////////////////
+fn dispatch(vector: u8, stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    super::stats::measure(vector, || {
+        // Software interrupts on vectors the APIC doesn't deliver are neither in service nor ended
+        let routed = apic::is_routed(vector);
+        if routed && super::spurious::check(vector) {
+            return;
+        }
+        let handled = {
+            let mut vectors = VECTORS.lock();
+            let state = &mut vectors[index(vector).unwrap()];
+            // Every handler of a shared vector runs, more than one device can be waiting
+            state.handlers.iter_mut().fold(false, |handled, registration| {
+                registration.handler.call() == IrqReturn::Handled || handled
+            })
+        };
+        if !handled {
+            UNHANDLED.fetch_add(1, Ordering::Relaxed);
+        }
+        if routed {
+            unsafe { apic::apic_send_eoi(this_cpu().apic_id()); }
+        }
+    })
+}
+
+/// Number of interrupts no handler claimed.
+pub fn unhandled_count() -> u64 {
+    UNHANDLED.load(Ordering::Relaxed)
+}
+
+/// Points every vector from `FIRST_VECTOR` up at the dispatcher.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    for (index, stub) in STUBS.iter().enumerate() {
+        idt[usize::from(FIRST_VECTOR) + index].set_handler_fn(*stub);
+    }
+}
+
+/// Generates one IDT entry per vector that passes its vector number on to `dispatch`.
+macro_rules! stubs {
+    ($($vector:literal)*) => {
+        [$({
+            extern "x86-interrupt" fn stub(stack_frame: &mut InterruptStackFrame) {
+                dispatch($vector, stack_frame);
+            }
+            stub as HandlerFunc
+        },)*]
+    };
+}
+
+static STUBS: [HandlerFunc; VECTOR_COUNT] = stubs![
+    32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47
+    48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63
+    64 65 66 67 68 69 70 71 72 73 74 75 76 77 78 79
+    80 81 82 83 84 85 86 87 88 89 90 91 92 93 94 95
+    96 97 98 99 100 101 102 103 104 105 106 107 108 109 110 111
+    112 113 114 115 116 117 118 119 120 121 122 123 124 125 126 127
+    128 129 130 131 132 133 134 135 136 137 138 139 140 141 142 143
+    144 145 146 147 148 149 150 151 152 153 154 155 156 157 158 159
+    160 161 162 163 164 165 166 167 168 169 170 171 172 173 174 175
+    176 177 178 179 180 181 182 183 184 185 186 187 188 189 190 191
+    192 193 194 195 196 197 198 199 200 201 202 203 204 205 206 207
+    208 209 210 211 212 213 214 215 216 217 218 219 220 221 222 223
+    224 225 226 227 228 229 230 231 232 233 234 235 236 237 238 239
+    240 241 242 243 244 245 246 247 248 249 250 251 252 253 254 255
+];
+
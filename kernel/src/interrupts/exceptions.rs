@@ -0,0 +1,486 @@
+//! Handlers for the CPU exceptions.
+//!
+//! Except for the debug and breakpoint traps, no handler returns to the faulting instruction,
+//! because it would just fault again. Exceptions caused by the code of the running thread kill
+//! that thread, exceptions that mean the machine or the kernel itself is broken panic. Both
+//! print a crash report with the decoded error code and the registers first.
+//!
+//! Control protection exceptions (#CP, vector 21) are not handled, the IDT of the x86_64 crate
+//! treats that vector as reserved. Shadow stacks and indirect branch tracking are never enabled,
+//! so it can't be raised.
+
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+use core::fmt;
+
+use crate::{println, gdt, threading};
+use crate::backtrace::{self, Backtrace};
+use crate::percpu::InterruptGs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    DivideError,
+    Debug,
+    Breakpoint,
+    Overflow,
+    BoundRangeExceeded,
+    InvalidOpcode,
+    DeviceNotAvailable,
+    DoubleFault,
+    InvalidTss,
+    SegmentNotPresent,
+    StackSegmentFault,
+    GeneralProtectionFault,
+    PageFault,
+    X87FloatingPoint,
+    AlignmentCheck,
+    MachineCheck,
+    SimdFloatingPoint,
+    Virtualization,
+    Security,
+}
+
+/// What happens after an exception has been reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// The exception is harmless, execution continues
+    Resume,
+    /// The running thread did something wrong and is killed
+    KillThread,
+    /// The kernel can't continue
+    Panic,
+}
+
+impl Exception {
+    pub fn vector(self) -> u8 {
+        match self {
+            Exception::DivideError => 0,
+            Exception::Debug => 1,
+            Exception::Breakpoint => 3,
+            Exception::Overflow => 4,
+            Exception::BoundRangeExceeded => 5,
+            Exception::InvalidOpcode => 6,
+            Exception::DeviceNotAvailable => 7,
+            Exception::DoubleFault => 8,
+            Exception::InvalidTss => 10,
+            Exception::SegmentNotPresent => 11,
+            Exception::StackSegmentFault => 12,
+            Exception::GeneralProtectionFault => 13,
+            Exception::PageFault => 14,
+            Exception::X87FloatingPoint => 16,
+            Exception::AlignmentCheck => 17,
+            Exception::MachineCheck => 18,
+            Exception::SimdFloatingPoint => 19,
+            Exception::Virtualization => 20,
+            Exception::Security => 30,
+        }
+    }
+
+    /// The short name from the manuals, like `#GP`.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Exception::DivideError => "#DE",
+            Exception::Debug => "#DB",
+            Exception::Breakpoint => "#BP",
+            Exception::Overflow => "#OF",
+            Exception::BoundRangeExceeded => "#BR",
+            Exception::InvalidOpcode => "#UD",
+            Exception::DeviceNotAvailable => "#NM",
+            Exception::DoubleFault => "#DF",
+            Exception::InvalidTss => "#TS",
+            Exception::SegmentNotPresent => "#NP",
+            Exception::StackSegmentFault => "#SS",
+            Exception::GeneralProtectionFault => "#GP",
+            Exception::PageFault => "#PF",
+            Exception::X87FloatingPoint => "#MF",
+            Exception::AlignmentCheck => "#AC",
+            Exception::MachineCheck => "#MC",
+            Exception::SimdFloatingPoint => "#XM",
+            Exception::Virtualization => "#VE",
+            Exception::Security => "#SX",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Exception::DivideError => "DIVIDE ERROR",
+            Exception::Debug => "DEBUG",
+            Exception::Breakpoint => "BREAKPOINT",
+            Exception::Overflow => "OVERFLOW",
+            Exception::BoundRangeExceeded => "BOUND RANGE EXCEEDED",
+            Exception::InvalidOpcode => "INVALID OPCODE",
+            Exception::DeviceNotAvailable => "DEVICE NOT AVAILABLE",
+            Exception::DoubleFault => "DOUBLE FAULT",
+            Exception::InvalidTss => "INVALID TSS",
+            Exception::SegmentNotPresent => "SEGMENT NOT PRESENT",
+            Exception::StackSegmentFault => "STACK SEGMENT FAULT",
+            Exception::GeneralProtectionFault => "GENERAL PROTECTION FAULT",
+            Exception::PageFault => "PAGE FAULT",
+            Exception::X87FloatingPoint => "X87 FLOATING POINT",
+            Exception::AlignmentCheck => "ALIGNMENT CHECK",
+            Exception::MachineCheck => "MACHINE CHECK",
+            Exception::SimdFloatingPoint => "SIMD FLOATING POINT",
+            Exception::Virtualization => "VIRTUALIZATION",
+            Exception::Security => "SECURITY",
+        }
+    }
+
+    pub fn policy(self) -> Policy {
+        match self {
+            Exception::Debug | Exception::Breakpoint => Policy::Resume,
+            // Broken descriptor tables, hardware errors and faults while handling a fault
+            Exception::DoubleFault
+            | Exception::InvalidTss
+            | Exception::SegmentNotPresent
+            | Exception::MachineCheck
+            | Exception::Virtualization
+            | Exception::Security => Policy::Panic,
+            _ => Policy::KillThread,
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}, vector {})", self.name(), self.mnemonic(), self.vector())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Error codes
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// The error code of exceptions that refer to a segment selector or an IDT entry.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(u64);
+
+impl SelectorErrorCode {
+    pub fn new(error_code: u64) -> Self {
+        Self(error_code)
+    }
+
+    /// The exception happened while delivering an external interrupt.
+    pub fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    pub fn index(self) -> u16 {
+        ((self.0 >> 3) & 0x1FFF) as u16
+    }
+
+    /// A general protection fault has an error code of 0 when no segment is involved.
+    pub fn is_null(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl fmt::Debug for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SelectorErrorCode")
+            .field("external", &self.external())
+            .field("table", &self.table())
+            .field("index", &self.index())
+            .finish()
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_null() {
+            return write!(f, "0 (not segment related)");
+        }
+        write!(f, "{:#x} ({:?} entry {}", self.0, self.table(), self.index())?;
+        if self.external() {
+            write!(f, ", external event")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The error code an exception pushed, decoded where the format is known.
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorCode {
+    None,
+    Selector(SelectorErrorCode),
+    PageFault { code: PageFaultErrorCode, address: VirtAddr },
+    Raw(u64),
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorCode::None => write!(f, "none"),
+            ErrorCode::Selector(selector) => write!(f, "{}", selector),
+            ErrorCode::PageFault { code, address } => write!(f, "{:?} accessing {:?}", code, address),
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Registers
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// The registers of the interrupted code, together with the stack frame the CPU pushed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Registers {
+    pub rax: u64, pub rbx: u64, pub rcx: u64, pub rdx: u64,
+    pub rsi: u64, pub rdi: u64, pub rbp: u64,
+    pub r8: u64, pub r9: u64, pub r10: u64, pub r11: u64,
+    pub r12: u64, pub r13: u64, pub r14: u64, pub r15: u64,
+    pub cr0: u64, pub cr2: u64, pub cr3: u64, pub cr4: u64,
+}
+
+impl Registers {
+    /// Reads the registers, this has to be the first thing a handler does. The handler prologue
+    /// can already have used some of the scratch registers (rax, rcx, rdx, rsi, rdi, r8 to r11),
+    /// so their values are a hint only.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut registers = Self::default();
+        unsafe {
+            llvm_asm!(""
+                : "={rax}"(registers.rax), "={rbx}"(registers.rbx), "={rcx}"(registers.rcx),
+                  "={rdx}"(registers.rdx), "={rsi}"(registers.rsi), "={rdi}"(registers.rdi),
+                  "={r8}"(registers.r8), "={r9}"(registers.r9), "={r10}"(registers.r10),
+                  "={r11}"(registers.r11), "={r12}"(registers.r12), "={r13}"(registers.r13),
+                  "={r14}"(registers.r14), "={r15}"(registers.r15)
+                ::: "intel", "volatile"
+            );
+            // Frame pointers are enabled, so the prologue pushed the interrupted rbp first
+            let frame: *const u64;
+            llvm_asm!("mov $0, rbp" : "=r"(frame) ::: "intel");
+            registers.rbp = *frame;
+            llvm_asm!("mov $0, cr0" : "=r"(registers.cr0) ::: "intel");
+            llvm_asm!("mov $0, cr2" : "=r"(registers.cr2) ::: "intel");
+            llvm_asm!("mov $0, cr3" : "=r"(registers.cr3) ::: "intel");
+            llvm_asm!("mov $0, cr4" : "=r"(registers.cr4) ::: "intel");
+        }
+        registers
+    }
+}
+
+/// Formats the registers together with the interrupt stack frame.
+pub struct RegisterDump<'a>(pub &'a Registers, pub &'a InterruptStackFrame);
+
+impl fmt::Display for RegisterDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let RegisterDump(r, frame) = self;
+        writeln!(f, "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}", r.rax, r.rbx, r.rcx, r.rdx)?;
+        writeln!(f, "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}", r.rsi, r.rdi, r.rbp, frame.stack_pointer.as_u64())?;
+        writeln!(f, "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}", r.r8, r.r9, r.r10, r.r11)?;
+        writeln!(f, "R12={:016x} R13={:016x} R14={:016x} R15={:016x}", r.r12, r.r13, r.r14, r.r15)?;
+        writeln!(
+            f, "RIP={:016x} RFLAGS={:016x} CS={:04x} SS={:04x}",
+            frame.instruction_pointer.as_u64(), frame.cpu_flags, frame.code_segment, frame.stack_segment,
+        )?;
+        write!(f, "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}", r.cr0, r.cr2, r.cr3, r.cr4)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Reporting
+///////////////////////////////////////////////////////////////////////////////////////////////////
+fn report(exception: Exception, stack_frame: &InterruptStackFrame, error_code: ErrorCode, registers: &Registers) {
+    println!("EXCEPTION: {}", exception);
+    println!("Error code: {}", error_code);
+    if let Some(thread_id) = threading::current_thread_id_unlocked() {
+        println!("Thread: {}", thread_id);
+    }
+    println!("{}", RegisterDump(registers, stack_frame));
+    backtrace::print(&Backtrace::from_frame(stack_frame.instruction_pointer.as_u64(), registers.rbp));
+}
+
+/// Reports an exception and applies its policy, for exceptions that can't resume.
+fn fatal(exception: Exception, stack_frame: &InterruptStackFrame, error_code: ErrorCode, registers: &Registers) -> ! {
+    report(exception, stack_frame, error_code, registers);
+    match exception.policy() {
+        Policy::KillThread => threading::kill_current_thread(),
+        Policy::Resume | Policy::Panic => panic!("EXCEPTION: {}", exception),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// IDT entries
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Installs the handlers for all exceptions.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    unsafe {
+        // Lazily backed stacks grow through page faults, which can't push onto the stack
+        idt.page_fault
+            .set_handler_fn(page_fault_handler)
+            .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+    }
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    unsafe {
+        idt.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    }
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.security_exception.set_handler_fn(security_exception_handler);
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Exception handlers
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Breakpoint handler
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
+    println!("EXCEPTION: {}\n{:#?}", Exception::Breakpoint, stack_frame);
+}
+
+/// Debug handler, single steps and hardware breakpoints
+extern "x86-interrupt" fn debug_handler(stack_frame: &mut InterruptStackFrame) {
+    println!("EXCEPTION: {}\n{:#?}", Exception::Debug, stack_frame);
+}
+
+/// Double fault handler
+extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: u64) -> ! {
+    let registers = Registers::capture();
+    fatal(Exception::DoubleFault, stack_frame, ErrorCode::Raw(error_code), &registers);
+}
+
+/// Machine check handler
+extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut InterruptStackFrame) -> ! {
+    let registers = Registers::capture();
+    fatal(Exception::MachineCheck, stack_frame, ErrorCode::None, &registers);
+}
+
+/// Page fault handler
+/// Faults in lazily backed regions get resolved, any other fault kills the faulting thread.
+extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode) {
+    use x86_64::registers::control::Cr2;
+
+    let registers = Registers::capture();
+    let _gs = InterruptGs::enter(stack_frame);
+    let address = Cr2::read();
+    if gdt::ist_stack_contains(gdt::PAGE_FAULT_IST_INDEX, stack_frame.stack_pointer) {
+        nested_page_fault(stack_frame, error_code, address, &registers);
+    }
+    let reason = match crate::memory::fault::handle_page_fault(address, error_code) {
+        Ok(()) => return,
+        Err(reason) => reason,
+    };
+
+    println!("Reason: {:?}", reason);
+    fatal(Exception::PageFault, stack_frame, ErrorCode::PageFault { code: error_code, address }, &registers);
+}
+
+/// A page fault while the page fault handler was running, on the same interrupt stack. The CPU
+/// started the stack over at its top, so the frame of the outer fault is gone and neither fault
+/// can return. The outer handler may hold the output locks, and it will never release them.
+fn nested_page_fault(
+    stack_frame: &InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+    address: VirtAddr,
+    registers: &Registers,
+) -> ! {
+    unsafe {
+        crate::vga_buffer::WRITER.force_unlock();
+        crate::serial::SERIAL1.force_unlock();
+    }
+    println!("Page fault in the page fault handler");
+    report(Exception::PageFault, stack_frame, ErrorCode::PageFault { code: error_code, address }, registers);
+    panic!("EXCEPTION: nested {}", Exception::PageFault);
+}
+
+/// Generates handlers for exceptions that end in `fatal`, with or without an error code.
+macro_rules! fatal_handlers {
+    ($($handler:ident => $exception:ident,)*) => {$(
+        extern "x86-interrupt" fn $handler(stack_frame: &mut InterruptStackFrame) {
+            let registers = Registers::capture();
+            let _gs = InterruptGs::enter(stack_frame);
+            fatal(Exception::$exception, stack_frame, ErrorCode::None, &registers);
+        }
+    )*};
+}
+
+macro_rules! fatal_handlers_with_error_code {
+    ($($handler:ident => $exception:ident, $decode:expr;)*) => {$(
+        extern "x86-interrupt" fn $handler(stack_frame: &mut InterruptStackFrame, error_code: u64) {
+            let registers = Registers::capture();
+            let _gs = InterruptGs::enter(stack_frame);
+            fatal(Exception::$exception, stack_frame, $decode(error_code), &registers);
+        }
+    )*};
+}
+
+fatal_handlers! {
+    divide_error_handler => DivideError,
+    overflow_handler => Overflow,
+    bound_range_exceeded_handler => BoundRangeExceeded,
+    invalid_opcode_handler => InvalidOpcode,
+    device_not_available_handler => DeviceNotAvailable,
+    x87_floating_point_handler => X87FloatingPoint,
+    simd_floating_point_handler => SimdFloatingPoint,
+    virtualization_handler => Virtualization,
+}
+
+fatal_handlers_with_error_code! {
+    invalid_tss_handler => InvalidTss, selector;
+    segment_not_present_handler => SegmentNotPresent, selector;
+    stack_segment_fault_handler => StackSegmentFault, selector;
+    general_protection_fault_handler => GeneralProtectionFault, selector;
+    alignment_check_handler => AlignmentCheck, ErrorCode::Raw;
+    security_exception_handler => Security, ErrorCode::Raw;
+}
+
+fn selector(error_code: u64) -> ErrorCode {
+    ErrorCode::Selector(SelectorErrorCode::new(error_code))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[test_case]
+fn test_breakpoint_exception() {
+    // invoke a breakpoint exception
+    x86_64::instructions::interrupts::int3();
+}
+
+#[test_case]
+fn test_selector_error_code() {
+    // IDT entry 0x20 during an external interrupt
+    let code = SelectorErrorCode::new(0x20 << 3 | 0b011);
+    assert!(code.external());
+    assert_eq!(code.table(), DescriptorTable::Idt);
+    assert_eq!(code.index(), 0x20);
+
+    let code = SelectorErrorCode::new(2 << 3 | 0b100);
+    assert!(!code.external());
+    assert_eq!(code.table(), DescriptorTable::Ldt);
+    assert_eq!(code.index(), 2);
+    assert!(SelectorErrorCode::new(0).is_null());
+}
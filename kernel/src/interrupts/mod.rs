@@ -0,0 +1,179 @@
+pub mod deferred;
+pub mod dispatch;
+pub mod exceptions;
+pub mod nmi;
+pub mod spurious;
+pub mod stats;
+pub mod watchdog;
+
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+use pic8259_simple::ChainedPics;
+use spin;
+
+use crate::{print, println, hlt_loop, apic};
+use crate::percpu::this_cpu;
+
+use deferred::Work;
+use dispatch::IrqReturn;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// PIC
+///////////////////////////////////////////////////////////////////////////////////////////////////
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+pub const PIC_OFFSET: u8 = 32;
+
+// pub static PICS: spin::Mutex<ChainedPics> =
+//     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+pub fn initialize_apic() {
+    register_default_handlers();
+
+    unsafe {
+        apic::disable_pic();
+
+        crate::hardware::rtc::enable_rtc(6); //Default value of 1024 hz
+
+        let apic_id = this_cpu().apic_id();
+        apic::enable_apic(apic_id);
+
+        // Default IRQs
+        // apic::ioapic_set_irq(0, apic_id, InterruptIndex::Timer.as_u8());
+        apic::ioapic_set_irq(1, apic_id, InterruptIndex::Keyboard.as_u8());
+        apic::ioapic_set_irq(8, apic_id, InterruptIndex::RTC.as_u8());
+
+        apic::apic_set_timer(apic_id);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_OFFSET,
+    Keyboard = PIC_OFFSET + 1,
+
+    RTC = PIC_OFFSET + 8,
+    ACPI = PIC_OFFSET + 9,
+
+    PrimaryATA = PIC_OFFSET + 14,
+    SecondaryATA = PIC_OFFSET + 15,
+}
+
+impl InterruptIndex {
+    fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_vector(vector: u8) -> Option<Self> {
+        use InterruptIndex::*;
+        [Timer, Keyboard, RTC, ACPI, PrimaryATA, SecondaryATA]
+            .iter()
+            .copied()
+            .find(|index| index.as_u8() == vector)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// IDT
+///////////////////////////////////////////////////////////////////////////////////////////////////
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+
+        // Exceptions
+        exceptions::install(&mut idt);
+        nmi::install(&mut idt);
+
+        // Interrupts, everything but the spurious vectors goes through the dispatcher
+        dispatch::install(&mut idt);
+        spurious::install(&mut idt);
+
+        idt
+    };
+}
+
+/// Initialization
+pub fn init_idt() {
+    IDT.load();
+}
+
+/// Registers the handlers of the devices the kernel drives itself.
+fn register_default_handlers() {
+    dispatch::init();
+    deferred::init();
+    spurious::reserve_vectors();
+
+    let null = core::ptr::null_mut();
+    dispatch::register_fn(InterruptIndex::Timer.as_u8(), "LAPIC timer", false, timer_interrupt, null)
+        .expect("failed to register the timer handler");
+    dispatch::register_fn(InterruptIndex::Keyboard.as_u8(), "keyboard", false, keyboard_interrupt, null)
+        .expect("failed to register the keyboard handler");
+    dispatch::register_fn(InterruptIndex::RTC.as_u8(), "RTC", false, rtc_interrupt, null)
+        .expect("failed to register the RTC handler");
+    dispatch::register_fn(InterruptIndex::ACPI.as_u8(), "ACPI", false, acpi_interrupt, null)
+        .expect("failed to register the ACPI handler");
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// PIC handlers
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Timer interrupt handler
+fn timer_interrupt(_context: *mut ()) -> IrqReturn {
+    let _ = deferred::schedule(Work::new("timer tick", |_| print!("."), 0));
+    // unsafe {
+    //     PICS.lock()
+    //         .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    // }
+    IrqReturn::Handled
+}
+
+/// Keyboard interrupt handler
+fn keyboard_interrupt(_context: *mut ()) -> IrqReturn {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    crate::task::keyboard::add_scancode(scancode);
+
+    let _ = deferred::schedule(Work::new("keyboard log", log_keyboard_interrupt, u64::from(scancode)));
+
+    // unsafe {
+    //     PICS.lock()
+    //         .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    // }
+    IrqReturn::Handled
+}
+
+fn log_keyboard_interrupt(scancode: u64) {
+    debug!("Keyboard interrupt! (scancode {:#04x})", scancode);
+}
+
+fn acpi_interrupt(_context: *mut ()) -> IrqReturn {
+    let _ = deferred::schedule(Work::new("ACPI log", |_| println!("ACPI INTERRUPT!"), 0));
+
+    // unsafe {
+    //     PICS.lock()
+    //         .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    // }
+    IrqReturn::Handled
+}
+
+fn rtc_interrupt(_context: *mut ()) -> IrqReturn {
+    //TODO: Probably want to use this irq to increment a "tick" global variable
+    //      This way, I can pretty easily implement a sleep function
+    use core::sync::atomic::Ordering;
+    crate::hardware::rtc::TICK_COUNT.fetch_add(1, Ordering::SeqCst);
+    // The LAPIC timer doesn't run yet, so the RTC is the only tick the watchdog counts
+    watchdog::touch();
+    // if crate::hardware::rtc::TICK_COUNT.load(Ordering::SeqCst) > 16384 {
+    //     debug!("hi 16384");
+    // }
+    unsafe {
+        use cpuio::{inb, outb};
+        outb(0x0C, 0x70);
+        inb(0x71);
+    }
+    IrqReturn::Handled
+}
@@ -0,0 +1,45 @@
+//! Non-maskable interrupts.
+//!
+//! An NMI can arrive at any point, also while the interrupted code holds a lock or runs on a
+//! broken stack, so the handler runs on its own interrupt stack and doesn't lock anything. The
+//! watchdog gets the first look, any other NMI is counted and logged as deferred work.
+
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::deferred::{self, Work};
+use super::exceptions::Registers;
+use super::watchdog;
+use crate::gdt;
+use crate::percpu::InterruptGs;
+
+static UNKNOWN: AtomicU64 = AtomicU64::new(0);
+
+/// Number of NMIs that weren't raised by the watchdog.
+pub fn unknown_count() -> u64 {
+    UNKNOWN.load(Ordering::Relaxed)
+}
+
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.non_maskable_interrupt
+            .set_handler_fn(nmi_handler)
+            .set_stack_index(gdt::NMI_IST_INDEX);
+    }
+}
+
+/// NMI handler
+extern "x86-interrupt" fn nmi_handler(stack_frame: &mut InterruptStackFrame) {
+    let registers = Registers::capture();
+    let _gs = InterruptGs::enter(stack_frame);
+    if watchdog::handle_nmi(stack_frame, &registers) {
+        return;
+    }
+    let count = UNKNOWN.fetch_add(1, Ordering::Relaxed) + 1;
+    let _ = deferred::schedule(Work::new("NMI log", log_unknown_nmi, count));
+}
+
+fn log_unknown_nmi(count: u64) {
+    warn!("Unknown NMI ({} so far)", count);
+}
@@ -0,0 +1,113 @@
+//! Spurious interrupts.
+//!
+//! An interrupt is spurious when the source withdrew it before the CPU accepted it. The local
+//! APIC then raises its spurious vector instead, and the 8259 PICs raise IRQ 7 or 15. Neither
+//! sets an in-service bit, so sending an EOI for them would end some other interrupt instead.
+//!
+//! Vectors that the IOAPIC or the local vector table route through the dispatcher are checked
+//! against the in-service register of the local APIC as well, an interrupt on them that isn't in
+//! service there didn't come from the APIC. Software interrupts on other vectors aren't checked.
+
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::apic;
+use crate::percpu::{this_cpu, InterruptGs};
+
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+static PIC: AtomicU64 = AtomicU64::new(0);
+static NOT_IN_SERVICE: AtomicU64 = AtomicU64::new(0);
+
+/// How many spurious interrupts were seen, by source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpuriousCounts {
+    /// Raised on the spurious vector of the local APIC
+    pub local_apic: u64,
+    /// Spurious IRQ 7 and 15 of the PICs
+    pub pic: u64,
+    /// Interrupts on a dispatched vector that was not in service in the local APIC
+    pub not_in_service: u64,
+}
+
+impl SpuriousCounts {
+    pub fn total(&self) -> u64 {
+        self.local_apic + self.pic + self.not_in_service
+    }
+}
+
+pub fn counts() -> SpuriousCounts {
+    SpuriousCounts {
+        local_apic: LOCAL_APIC.load(Ordering::Relaxed),
+        pic: PIC.load(Ordering::Relaxed),
+        not_in_service: NOT_IN_SERVICE.load(Ordering::Relaxed),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// IDT entries
+///////////////////////////////////////////////////////////////////////////////////////////////////
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(local_apic_spurious_handler);
+    idt[usize::from(apic::PIC_MASTER_SPURIOUS_VECTOR)].set_handler_fn(pic_master_spurious_handler);
+    idt[usize::from(apic::PIC_SLAVE_SPURIOUS_VECTOR)].set_handler_fn(pic_slave_spurious_handler);
+}
+
+/// Keeps the dispatcher from handing out the vectors that have spurious handlers. The local
+/// APIC spurious vector is reserved by the dispatcher itself.
+pub(super) fn reserve_vectors() {
+    super::dispatch::reserve_vector(apic::PIC_MASTER_SPURIOUS_VECTOR);
+    super::dispatch::reserve_vector(apic::PIC_SLAVE_SPURIOUS_VECTOR);
+}
+
+/// Returns true if an interrupt on a dispatched vector is spurious, after counting it. The
+/// dispatcher must neither run the handlers nor send an EOI for it then. Only meaningful for
+/// vectors where `apic::is_routed` is true.
+///
+/// Nothing is lost by skipping it, if a device did raise the vector it is still set in the
+/// interrupt request register and gets delivered again.
+pub(super) fn check(vector: u8) -> bool {
+    if apic::local_apic(this_cpu().apic_id()).in_service(vector) {
+        return false;
+    }
+    NOT_IN_SERVICE.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Handlers
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// The local APIC does not expect an EOI for its spurious vector
+extern "x86-interrupt" fn local_apic_spurious_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    super::stats::measure(apic::SPURIOUS_VECTOR, || {
+        LOCAL_APIC.fetch_add(1, Ordering::Relaxed);
+    });
+}
+
+/// IRQ 7 of the master PIC, real if the PIC has it in service
+extern "x86-interrupt" fn pic_master_spurious_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    super::stats::measure(apic::PIC_MASTER_SPURIOUS_VECTOR, || {
+        if unsafe { apic::pic_in_service() } & (1 << 7) != 0 {
+            // The PICs are masked, so there is no handler for a real one, just end it
+            unsafe { apic::pic_master_send_eoi(); }
+        } else {
+            PIC.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+}
+
+/// IRQ 15 of the slave PIC. The master did see a real IRQ 2 from the slave, so it needs an EOI
+/// either way.
+extern "x86-interrupt" fn pic_slave_spurious_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = InterruptGs::enter(stack_frame);
+    super::stats::measure(apic::PIC_SLAVE_SPURIOUS_VECTOR, || {
+        if unsafe { apic::pic_in_service() } & (1 << 15) != 0 {
+            unsafe { apic::pic_slave_send_eoi(); }
+        } else {
+            PIC.fetch_add(1, Ordering::Relaxed);
+        }
+        unsafe { apic::pic_master_send_eoi(); }
+    });
+}
@@ -0,0 +1,212 @@
+//! Per-vector interrupt statistics.
+//!
+//! Every CPU counts the interrupts it dispatched per vector in its per-CPU block. With latency
+//! tracking enabled, the time from the entry into the dispatcher to its exit is measured with
+//! the TSC as well. `table` puts it all together like `/proc/interrupts`.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::spurious::{self, SpuriousCounts};
+use super::{dispatch, InterruptIndex};
+use crate::apic;
+use crate::percpu::{self, this_cpu};
+
+static LATENCY_TRACKING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorStats {
+    pub count: u64,
+    /// Interrupts that were timed, only these count towards the latency
+    pub timed: u64,
+    pub total_cycles: u64,
+    pub min_cycles: u64,
+    pub max_cycles: u64,
+}
+
+impl VectorStats {
+    const ZERO: Self = Self { count: 0, timed: 0, total_cycles: 0, min_cycles: u64::MAX, max_cycles: 0 };
+
+    fn record(&mut self, cycles: Option<u64>) {
+        self.count += 1;
+        if let Some(cycles) = cycles {
+            self.timed += 1;
+            self.total_cycles += cycles;
+            self.min_cycles = self.min_cycles.min(cycles);
+            self.max_cycles = self.max_cycles.max(cycles);
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.timed += other.timed;
+        self.total_cycles += other.total_cycles;
+        self.min_cycles = self.min_cycles.min(other.min_cycles);
+        self.max_cycles = self.max_cycles.max(other.max_cycles);
+    }
+
+    pub fn average_cycles(&self) -> Option<u64> {
+        if self.timed == 0 { None } else { Some(self.total_cycles / self.timed) }
+    }
+}
+
+/// The statistics of one CPU.
+pub struct InterruptStats {
+    /// Only written by the owning CPU with interrupts disabled
+    vectors: UnsafeCell<[VectorStats; 256]>,
+}
+
+impl InterruptStats {
+    pub const fn new() -> Self {
+        Self { vectors: UnsafeCell::new([VectorStats::ZERO; 256]) }
+    }
+
+    pub fn get(&self, vector: u8) -> VectorStats {
+        unsafe { (*self.vectors.get())[usize::from(vector)] }
+    }
+
+    fn record(&self, vector: u8, cycles: Option<u64>) {
+        unsafe { (*self.vectors.get())[usize::from(vector)].record(cycles) }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Recording
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Turns timing the handlers on or off. Reading the TSC twice per interrupt is cheap, but not free.
+pub fn set_latency_tracking(enabled: bool) {
+    LATENCY_TRACKING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn latency_tracking() -> bool {
+    LATENCY_TRACKING.load(Ordering::Relaxed)
+}
+
+/// Runs the handling of an interrupt on `vector` and records it for this CPU. Must run with
+/// interrupts disabled, like every interrupt handler.
+pub(super) fn measure<T>(vector: u8, handle: impl FnOnce() -> T) -> T {
+    let start = if latency_tracking() { Some(rdtsc()) } else { None };
+    let result = handle();
+    let cycles = start.map(|start| rdtsc().wrapping_sub(start));
+    this_cpu().interrupt_stats().record(vector, cycles);
+    result
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Table
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A snapshot of the statistics of all CPUs, which formats as a table.
+pub struct InterruptTable {
+    cpus: usize,
+    rows: Vec<Row>,
+    spurious: SpuriousCounts,
+    unhandled: u64,
+}
+
+struct Row {
+    vector: u8,
+    source: String,
+    /// Count per CPU
+    counts: Vec<u64>,
+    /// All CPUs together
+    total: VectorStats,
+}
+
+/// Collects the vectors that fired or have handlers.
+pub fn table() -> InterruptTable {
+    let cpus: Vec<_> = percpu::cpus().collect();
+    let mut rows = Vec::new();
+    for vector in dispatch::FIRST_VECTOR..=255 {
+        let per_cpu: Vec<VectorStats> = cpus.iter().map(|cpu| cpu.interrupt_stats().get(vector)).collect();
+        let names = dispatch::handler_names(vector);
+        if names.is_empty() && per_cpu.iter().all(|stats| stats.count == 0) {
+            continue;
+        }
+        let mut total = VectorStats::ZERO;
+        per_cpu.iter().for_each(|stats| total.merge(stats));
+        rows.push(Row {
+            vector,
+            source: source(vector, &names),
+            counts: per_cpu.iter().map(|stats| stats.count).collect(),
+            total,
+        });
+    }
+    InterruptTable {
+        cpus: cpus.len(),
+        rows,
+        spurious: spurious::counts(),
+        unhandled: dispatch::unhandled_count(),
+    }
+}
+
+/// Names what raises a vector: the handlers registered for it, or what the kernel uses it for.
+fn source(vector: u8, handler_names: &[&str]) -> String {
+    if !handler_names.is_empty() {
+        return handler_names.join(", ");
+    }
+    match vector {
+        apic::SPURIOUS_VECTOR => String::from("LAPIC spurious"),
+        apic::PIC_MASTER_SPURIOUS_VECTOR | apic::PIC_SLAVE_SPURIOUS_VECTOR => String::from("PIC spurious"),
+        _ => match InterruptIndex::from_vector(vector) {
+            Some(index) => format!("{:?}", index),
+            None => String::from("-"),
+        },
+    }
+}
+
+impl fmt::Display for InterruptTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VEC ")?;
+        for cpu in 0..self.cpus {
+            write!(f, "{:>10}", format_args!("CPU{}", cpu))?;
+        }
+        writeln!(f, "  {:<20} {:>10} {:>10} {:>10}", "SOURCE", "MIN", "AVG", "MAX")?;
+
+        for row in &self.rows {
+            write!(f, "{:>3} ", row.vector)?;
+            for count in &row.counts {
+                write!(f, "{:>10}", count)?;
+            }
+            write!(f, "  {:<20}", row.source)?;
+            match row.total.average_cycles() {
+                Some(average) => writeln!(f, " {:>10} {:>10} {:>10}", row.total.min_cycles, average, row.total.max_cycles)?,
+                None => writeln!(f, " {:>10} {:>10} {:>10}", "-", "-", "-")?,
+            }
+        }
+
+        writeln!(
+            f, "SPU {} (LAPIC {}, PIC {}, not in service {})",
+            self.spurious.total(), self.spurious.local_apic, self.spurious.pic, self.spurious.not_in_service,
+        )?;
+        write!(f, "UNH {}", self.unhandled)
+    }
+}
+
+/// Logs the table, latencies are in TSC cycles.
+pub fn log_stats() {
+    for line in table().to_string().lines() {
+        info!("{}", line);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[test_case]
+fn test_vector_stats() {
+    let mut stats = VectorStats::ZERO;
+    stats.record(None);
+    assert_eq!(stats.average_cycles(), None);
+    stats.record(Some(100));
+    stats.record(Some(300));
+    assert_eq!(stats.count, 3);
+    assert_eq!(stats.timed, 2);
+    assert_eq!((stats.min_cycles, stats.average_cycles(), stats.max_cycles), (100, Some(200), 300));
+}
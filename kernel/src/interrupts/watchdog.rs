@@ -0,0 +1,223 @@
+//! Hard-lockup watchdog.
+//!
+//! A CPU that spins with interrupts disabled never gets to run another handler, so the
+//! watchdog checks on it from an NMI, which can't be masked. The first performance counter
+//! counts unhalted core cycles and raises an NMI through the local APIC every time it
+//! overflows. If the timer ticks of the CPU didn't advance for `timeout` seconds in between, it
+//! is locked up: the NMI handler dumps its registers and the running thread, and panics.
+//!
+//! The LAPIC timer doesn't run yet, so the RTC interrupt is the only timer tick it counts. A
+//! halted CPU doesn't count cycles, so an idle CPU gets no NMIs and isn't mistaken for a locked
+//! up one. That also means a CPU stuck in `hlt` with interrupts disabled isn't detected.
+
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use super::exceptions::{RegisterDump, Registers};
+use crate::apic::{self, registers::{DeliveryMode, Lvt, LvtEntry}};
+use crate::backtrace::{self, Backtrace};
+use crate::percpu::this_cpu;
+use crate::{println, threading};
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// Event select for unhalted core cycles, counted in user and kernel mode, with an interrupt
+/// on overflow
+const UNHALTED_CORE_CYCLES: u64 = 0x3C | 1 << 16 | 1 << 17 | 1 << 20 | 1 << 22;
+
+/// Number of NMIs per second, roughly, the core clock isn't exactly the TSC frequency
+const NMIS_PER_SECOND: u64 = 4;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static TIMEOUT_CYCLES: AtomicU64 = AtomicU64::new(0);
+static TSC_PER_SECOND: AtomicU64 = AtomicU64::new(0);
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+static COUNTER_WIDTH: AtomicU64 = AtomicU64::new(0);
+static PERFMON_VERSION: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogError {
+    /// The CPU has no architectural performance counter that counts unhalted core cycles
+    NoPerformanceCounter,
+    AlreadyRunning,
+}
+
+/// Watchdog state of one CPU, kept in its per-CPU block.
+pub struct WatchdogState {
+    /// Timer ticks the CPU serviced
+    ticks: AtomicU64,
+    /// Value of `ticks` at the last NMI
+    seen_ticks: AtomicU64,
+    /// TSC at the last NMI that saw the ticks advance
+    last_progress: AtomicU64,
+}
+
+impl WatchdogState {
+    pub const fn new() -> Self {
+        Self {
+            ticks: AtomicU64::new(0),
+            seen_ticks: AtomicU64::new(0),
+            last_progress: AtomicU64::new(0),
+        }
+    }
+
+    fn reset(&self, now: u64) {
+        self.seen_ticks.store(self.ticks.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.last_progress.store(now, Ordering::Relaxed);
+    }
+
+    /// Returns how many cycles the ticks have been stuck for, if that's more than `timeout`.
+    fn check(&self, now: u64, timeout: u64) -> Option<u64> {
+        let ticks = self.ticks.load(Ordering::Relaxed);
+        if ticks != self.seen_ticks.load(Ordering::Relaxed) {
+            self.seen_ticks.store(ticks, Ordering::Relaxed);
+            self.last_progress.store(now, Ordering::Relaxed);
+            return None;
+        }
+        let stuck = now.wrapping_sub(self.last_progress.load(Ordering::Relaxed));
+        if stuck > timeout { Some(stuck) } else { None }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Setup
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Starts the watchdog on this CPU. Interrupts must be enabled already, the TSC is calibrated
+/// against the RTC.
+pub fn init(timeout_seconds: u64) -> Result<(), WatchdogError> {
+    use core::arch::x86_64::__cpuid;
+
+    // Architectural performance monitoring leaf
+    let perfmon = unsafe { __cpuid(0xA) };
+    let version = u64::from(perfmon.eax & 0xFF);
+    let counters = (perfmon.eax >> 8) & 0xFF;
+    let width = u64::from((perfmon.eax >> 16) & 0xFF);
+    let event_mask_length = (perfmon.eax >> 24) & 0xFF;
+    // A set bit in EBX means the event is not available. Without a counter width the overflow
+    // can't be told apart from other NMIs.
+    if version == 0 || counters == 0 || width == 0 || event_mask_length == 0 || perfmon.ebx & 1 != 0 {
+        return Err(WatchdogError::NoPerformanceCounter);
+    }
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(WatchdogError::AlreadyRunning);
+    }
+
+    let tsc_per_second = tsc_frequency();
+    // Writes to IA32_PMC0 only set the low 32 bits and sign extend them
+    let period = (tsc_per_second / NMIS_PER_SECOND).min(i32::MAX as u64);
+    TIMEOUT_CYCLES.store(timeout_seconds * tsc_per_second, Ordering::Relaxed);
+    TSC_PER_SECOND.store(tsc_per_second, Ordering::Relaxed);
+    PERIOD.store(period, Ordering::Relaxed);
+    COUNTER_WIDTH.store(width, Ordering::Relaxed);
+    PERFMON_VERSION.store(version, Ordering::Relaxed);
+    debug!("NMI watchdog: {} s timeout, TSC at {} Hz, NMI every {} cycles", timeout_seconds, tsc_per_second, period);
+
+    let cpu = this_cpu();
+    cpu.watchdog().reset(rdtsc());
+    unsafe {
+        Msr::new(IA32_PERFEVTSEL0).write(0);
+        reload_counter();
+
+        let mut lvt = LvtEntry::from_raw(0);
+        lvt.set_delivery_mode(DeliveryMode::Nmi);
+        apic::local_apic(cpu.apic_id()).set_lvt(Lvt::PerformanceCounter, lvt);
+
+        if version >= 2 {
+            Msr::new(IA32_PERF_GLOBAL_CTRL).write(1);
+        }
+        Msr::new(IA32_PERFEVTSEL0).write(UNHALTED_CORE_CYCLES);
+    }
+    Ok(())
+}
+
+/// Measures the TSC frequency over 10ms of RTC ticks.
+fn tsc_frequency() -> u64 {
+    let start = rdtsc();
+    crate::hardware::rtc::sleep(0.01);
+    (rdtsc() - start) * 100
+}
+
+unsafe fn reload_counter() {
+    Msr::new(IA32_PMC0).write(PERIOD.load(Ordering::Relaxed).wrapping_neg());
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Ticks and NMIs
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Tells the watchdog that this CPU serviced a timer tick. Called from the RTC interrupt.
+pub fn touch() {
+    this_cpu().watchdog().ticks.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Handles the NMI if the watchdog counter raised it, and returns false otherwise. Doesn't
+/// return if the CPU is locked up.
+pub(super) fn handle_nmi(stack_frame: &InterruptStackFrame, registers: &Registers) -> bool {
+    if !RUNNING.load(Ordering::Relaxed) || !counter_overflowed() {
+        return false;
+    }
+    unsafe {
+        if PERFMON_VERSION.load(Ordering::Relaxed) >= 2 {
+            Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1);
+        }
+        reload_counter();
+        // The local APIC masks the entry when it delivers the NMI
+        let apic = apic::local_apic(this_cpu().apic_id());
+        let mut lvt = apic.lvt(Lvt::PerformanceCounter);
+        lvt.set_masked(false);
+        apic.set_lvt(Lvt::PerformanceCounter, lvt);
+    }
+
+    if let Some(stuck) = this_cpu().watchdog().check(rdtsc(), TIMEOUT_CYCLES.load(Ordering::Relaxed)) {
+        hard_lockup(stack_frame, registers, stuck);
+    }
+    true
+}
+
+/// The counter starts negative, it overflowed once the top bit is clear again.
+fn counter_overflowed() -> bool {
+    let value = unsafe { Msr::new(IA32_PMC0).read() };
+    value & 1 << (COUNTER_WIDTH.load(Ordering::Relaxed) - 1) == 0
+}
+
+fn hard_lockup(stack_frame: &InterruptStackFrame, registers: &Registers, stuck: u64) -> ! {
+    // The locked up code may hold the output locks, and it will never release them
+    unsafe {
+        crate::vga_buffer::WRITER.force_unlock();
+        crate::serial::SERIAL1.force_unlock();
+    }
+
+    let cpu = this_cpu();
+    let seconds = stuck / TSC_PER_SECOND.load(Ordering::Relaxed).max(1);
+    println!("NMI watchdog: hard lockup on CPU {} (APIC {}), no timer tick for {} s", cpu.index(), cpu.apic_id(), seconds);
+    match threading::current_thread_id_unlocked() {
+        Some(thread_id) => println!("Thread: {}", thread_id),
+        None => println!("Thread: none"),
+    }
+    println!("{}", RegisterDump(registers, stack_frame));
+    backtrace::print(&Backtrace::from_frame(stack_frame.instruction_pointer.as_u64(), registers.rbp));
+    panic!("hard lockup on CPU {}", cpu.index());
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Only ticks that stay the same for longer than the timeout are a lockup
+#[test_case]
+fn test_lockup_check() {
+    let state = WatchdogState::new();
+    state.reset(1000);
+    assert_eq!(state.check(1500, 1000), None);
+    state.ticks.fetch_add(1, Ordering::Relaxed);
+    assert_eq!(state.check(1800, 1000), None);
+    assert_eq!(state.check(2800, 1000), None);
+    assert_eq!(state.check(2801, 1000), Some(1001));
+}
@@ -20,7 +20,13 @@
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout)
+    panic!(
+        "allocation error: {:?} (heap size: {} bytes, used: {} bytes, limit: {} bytes)",
+        layout,
+        allocator::heap_size(),
+        allocator::heap_used(),
+        allocator::heap_limit(),
+    )
 }
 
 #[macro_use] extern crate lazy_static;
@@ -38,12 +44,14 @@ pub mod serial;
 pub mod vga_buffer;
 pub mod interrupts;
 pub mod gdt;
+pub mod percpu; // Per-CPU data through the GS base
 pub mod memory;
 pub mod allocator;
 pub mod task; // Basic implementation of cooperative multitasking
 pub mod threading; // Basic implementation of threading
 pub mod acpi_controller;
 pub mod apic;
+pub mod backtrace; // Stack walking and kernel symbols
 pub mod hardware;
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
@@ -61,6 +69,8 @@ pub fn hlt_loop() -> ! {
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
+    memory::protection::init();
+    memory::cow::init();
 
     // unsafe { interrupts::PICS.lock().initialize() };
 }
@@ -113,6 +123,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}", backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
     loop {}
 }
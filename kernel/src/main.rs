@@ -37,6 +37,8 @@ use kernel::{
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    kernel::serial_println!("{}", info);
+    kernel::backtrace::print(&kernel::backtrace::Backtrace::capture());
     loop {}
 }
 
@@ -51,18 +53,20 @@ fn panic(info: &PanicInfo) -> ! {
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 entry_point!(kernel_main);
 
+/// Seconds without a timer tick before the watchdog declares a hard lockup
+const WATCHDOG_TIMEOUT_SECONDS: u64 = 10;
+/// Heap the boot threads need for their stack regions and scheduler entries
+const THREAD_CREATION_HEAP: usize = 64 * 1024;
+
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    use kernel::memory::BootInfoFrameAllocator;
+    use kernel::memory::BitmapFrameAllocator;
     use x86_64::{structures::paging::MapperAllSizes, VirtAddr};
 
     kernel::logger::init().expect("Failed to load the kernel logger!");
 
     debug!("Hello world!");
 
-    let regions = boot_info.memory_map.iter();
-    let addr_ranges = regions.map(|r| r.range.start_addr()..r.range.end_addr());
-    let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-    let available_memory = frame_addresses.count() * 4;
+    let available_memory = kernel::memory::stats::usable_memory(&boot_info.memory_map) / 1024;
     debug!("Memory available: {} KiB", available_memory);
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
@@ -72,16 +76,21 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         *mapper = unsafe { Some(kernel::memory::init(phys_mem_offset)) };
         let mut frame_allocator = kernel::memory::FRAME_ALLOCATOR.lock();
         *frame_allocator = unsafe {
-            Some(BootInfoFrameAllocator::init(&boot_info.memory_map))
+            Some(BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset))
         };
         debug!("Mapper and frame allocator created!");
     }
 
-    let mut mapper = kernel::memory::MAPPER.lock();
-    let mut frame_allocator = kernel::memory::FRAME_ALLOCATOR.lock();
-
     kernel::init();
-    kernel::allocator::init_heap(mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap()).expect("Heap initialization failed!");
+    let physical_memory_size = boot_info.memory_map.iter().map(|r| r.range.end_addr()).max().unwrap_or(0);
+    kernel::memory::protection::enforce_w_xor_x(physical_memory_size);
+    kernel::gdt::add_ist_guard_pages(kernel::memory::MAPPER.lock().as_mut().unwrap());
+    {
+        let mut mapper = kernel::memory::MAPPER.lock();
+        let mut frame_allocator = kernel::memory::FRAME_ALLOCATOR.lock();
+        kernel::allocator::init_heap(mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap()).expect("Heap initialization failed!");
+        kernel::memory::address_space::init_kernel_half(frame_allocator.as_mut().unwrap());
+    }
 
     let acpi_controller = kernel::acpi_controller::AcpiController::new(phys_mem_offset.as_u64());
 
@@ -102,6 +111,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     kernel::interrupts::initialize_apic();
     x86_64::instructions::interrupts::enable();
 
+    if let Err(err) = kernel::interrupts::watchdog::init(WATCHDOG_TIMEOUT_SECONDS) {
+        debug!("NMI watchdog not started: {:?}", err);
+    }
+
     debug!("hi");
     kernel::hardware::rtc::sleep(2.0);
     debug!("hi 2 seconds later :D");
@@ -109,20 +122,37 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     #[cfg(test)]
     test_main();
 
-    let idle_thread = Thread::create(idle_thread, 2, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap()).unwrap();
-    with_scheduler(|s| s.set_idle_thread(idle_thread));
-
-    for _ in 0..10 {
-        let thread = Thread::create(thread_entry, 2, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap()).unwrap();
+    // The mapper and frame allocator must be unlocked again before switching threads,
+    // otherwise exited threads can never give their stacks back
+    {
+        let mut mapper = kernel::memory::MAPPER.lock();
+        let mut frame_allocator = kernel::memory::FRAME_ALLOCATOR.lock();
+        // The heap can't grow while both are locked, the threads below get their memory up front
+        kernel::allocator::reserve(THREAD_CREATION_HEAP, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+            .expect("failed to reserve heap for the threads");
+
+        let idle_thread = Thread::create(idle_thread, 2, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap()).unwrap();
+        with_scheduler(|s| s.set_idle_thread(idle_thread));
+
+        let deferred_work_thread = Thread::create(kernel::interrupts::deferred::worker_thread, 2, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap()).unwrap();
+        with_scheduler(|s| s.add_new_thread(deferred_work_thread));
+
+        for _ in 0..10 {
+            let thread = Thread::create(thread_entry, 2, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap()).unwrap();
+            with_scheduler(|s| s.add_new_thread(thread));
+        }
+        let thread =
+            Thread::create_from_closure(|| thread_entry(), 2, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+                .unwrap();
         with_scheduler(|s| s.add_new_thread(thread));
+
+        // let keyboard_thread = Thread::create(thread_keyboard, 2, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap()).unwrap();
+        // with_scheduler(|s| s.add_new_thread(keyboard_thread));
     }
-    let thread =
-        Thread::create_from_closure(|| thread_entry(), 2, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
-            .unwrap();
-    with_scheduler(|s| s.add_new_thread(thread));
 
-    // let keyboard_thread = Thread::create(thread_keyboard, 2, &mut mapper, &mut frame_allocator).unwrap();
-    // with_scheduler(|s| s.add_new_thread(keyboard_thread));
+    kernel::memory::vma::debug_print();
+    kernel::memory::stats::log_stats();
+    kernel::interrupts::stats::log_stats();
 
     debug!("It did not crash!");
     // loop {}
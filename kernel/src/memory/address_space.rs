@@ -0,0 +1,363 @@
+use x86_64::{
+    registers::control::{Cr3, Cr4, Cr4Flags},
+    structures::paging::{
+        mapper,
+        page_table::PageTableEntry,
+        FrameAllocator,
+        FrameDeallocator,
+        Mapper,
+        OffsetPageTable,
+        Page,
+        PageTable,
+        PageTableFlags,
+        PageSize,
+        PhysFrame,
+        Size4KiB,
+        Size2MiB,
+        Size1GiB,
+    },
+    VirtAddr,
+    PhysAddr,
+};
+
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+
+use crate::allocator::{HEAP_START, HEAP_MAX_SIZE};
+use super::vma::{VMA_WINDOW_START, VMA_WINDOW_END};
+
+static KERNEL_P4_FRAME: AtomicU64 = AtomicU64::new(0);
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_PCID: AtomicU16 = AtomicU16::new(1);
+/// Bumped whenever a kernel mapping is removed or restricted, see `kernel_mapping_changed`
+static KERNEL_GENERATION: AtomicU64 = AtomicU64::new(0);
+/// The level 4 frame and kernel generation whose TLB entries each PCID still holds
+static PCID_OWNERS: spin::Mutex<[(u64, u64); PCID_COUNT as usize]> = spin::Mutex::new([(0, 0); PCID_COUNT as usize]);
+
+const PCID_COUNT: u16 = 4096;
+/// Set in the value written to CR3 to keep the TLB entries of the PCID
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Kernel half
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Prepares the active page table to be shared as the kernel half of every address space.
+///
+/// Address spaces copy the level 4 entries of the kernel, so the kernel must never add a level 4
+/// entry after the first address space is created. This function creates the level 3 tables for
+/// the regions the kernel still grows into (the heap and the VMA window) up front, so later
+/// kernel mappings end up in tables that are shared with every address space.
+pub fn init_kernel_half(frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
+    let (level_4_frame, _) = Cr3::read();
+    KERNEL_P4_FRAME.store(level_4_frame.start_address().as_u64(), Ordering::SeqCst);
+
+    let level_4_table = unsafe { &mut *table_ptr(level_4_frame) };
+    let heap_indexes = p4_index(HEAP_START as u64)..=p4_index((HEAP_START + HEAP_MAX_SIZE - 1) as u64);
+    let vma_indexes = p4_index(VMA_WINDOW_START)..=p4_index(VMA_WINDOW_END - 1);
+    for index in heap_indexes.chain(vma_indexes) {
+        let entry = &mut level_4_table[index];
+        if entry.is_unused() {
+            let frame = frame_allocator.allocate_frame().expect("out of frames for kernel page tables");
+            unsafe { (*table_ptr(frame)).zero(); }
+            // Nothing in these regions is code, so they are non-executable as a whole
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | super::protection::no_execute());
+        }
+    }
+
+    if supports_pcid() {
+        unsafe { Cr4::write(Cr4::read() | Cr4Flags::PCID); }
+        PCID_ENABLED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Switches back to the kernel page table, for threads that don't have an address space.
+pub fn activate_kernel_address_space() {
+    if KERNEL_P4_FRAME.load(Ordering::Relaxed) == 0 {
+        return; // no address spaces yet, so the kernel page table is still active
+    }
+    let frame = kernel_level_4_frame();
+    if Cr3::read().0 != frame {
+        unsafe { write_cr3(frame, 0); }
+    }
+}
+
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns the PCID in CR3, always 0 if PCIDs are disabled.
+pub fn active_pcid() -> u16 {
+    let value: u64;
+    unsafe { llvm_asm!("mov $0, cr3" : "=r"(value) ::: "intel", "volatile"); }
+    (value & 0xFFF) as u16
+}
+
+/// Has to be called after a kernel mapping was unmapped or lost permissions, and its TLB entry
+/// flushed with `invlpg`. That only flushes the entry for the active PCID, so every other PCID
+/// gets flushed completely the next time it is loaded.
+///
+/// Only the bootstrap CPU runs, so no other CPU can be using one of those PCIDs right now.
+pub fn kernel_mapping_changed() {
+    if PCID_ENABLED.load(Ordering::Relaxed) {
+        KERNEL_GENERATION.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_P4_FRAME.load(Ordering::Relaxed)))
+}
+
+/// Returns true if the level 4 entry at `index` belongs to the kernel half.
+fn is_kernel_entry(index: usize) -> bool {
+    let kernel_table = unsafe { &*table_ptr(kernel_level_4_frame()) };
+    !kernel_table[index].is_unused()
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Address space
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A separate set of page tables. The kernel half is shared with every other address space,
+/// everything else is private and freed, including its frames, when the address space is dropped.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    pcid: u16,
+}
+
+impl AddressSpace {
+    /// Creates a new address space with an empty user half.
+    pub fn new(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Option<Self> {
+        assert!(KERNEL_P4_FRAME.load(Ordering::Relaxed) != 0, "kernel half is not initialized");
+
+        let level_4_frame = frame_allocator.allocate_frame()?;
+        let level_4_table = unsafe { &mut *table_ptr(level_4_frame) };
+        let kernel_table = unsafe { &*table_ptr(kernel_level_4_frame()) };
+        level_4_table.zero();
+        for (entry, kernel_entry) in level_4_table.iter_mut().zip(kernel_table.iter()) {
+            if !kernel_entry.is_unused() {
+                *entry = kernel_entry.clone();
+            }
+        }
+
+        // PCID 0 belongs to the kernel page table
+        let pcid = NEXT_PCID.fetch_add(1, Ordering::Relaxed) % (PCID_COUNT - 1) + 1;
+        Some(Self { level_4_frame, pcid })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// The PCID the address space is tagged with, only used if `pcid_enabled` returns true.
+    pub fn pcid(&self) -> u16 {
+        self.pcid
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Loads this address space into CR3, tagged with its PCID if the CPU supports them.
+    ///
+    /// This function is unsafe because the caller must guarantee that the address space stays
+    /// alive for as long as it is active.
+    pub unsafe fn activate(&self) {
+        if !self.is_active() {
+            write_cr3(self.level_4_frame, self.pcid);
+        }
+    }
+
+    /// Returns a mapper for this address space, which also sees the shared kernel half.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let physical_memory_offset = VirtAddr::new(super::PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+        unsafe { OffsetPageTable::new(&mut *table_ptr(self.level_4_frame), physical_memory_offset) }
+    }
+
+    /// Maps a fresh frame at `page` in the user half. The page is always user accessible.
+    pub fn map_user_page(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<PhysFrame, mapper::MapToError<Size4KiB>> {
+        assert!(
+            !is_kernel_entry(usize::from(page.p4_index())),
+            "{:?} lies in the kernel half", page
+        );
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(mapper::MapToError::FrameAllocationFailed)?;
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        unsafe { self.mapper().map_to(page, frame, flags, frame_allocator)?.flush(); }
+        Ok(frame)
+    }
+
+    /// Creates a copy of this address space that shares all user frames copy-on-write. Both
+    /// address spaces get read-only mappings of the shared frames, the first write to a page
+    /// gives the writer a private copy of it. Huge pages in the user half are not supported.
+    ///
+    /// If the copy runs out of frames, the partial child is freed again through `frame_allocator`.
+    /// Pages that were shared already stay read-only in the parent and are made writable again
+    /// by the next write fault.
+    pub fn clone_cow(
+        &mut self,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Option<AddressSpace> {
+        let child = AddressSpace::new(frame_allocator)?;
+        let level_4_table = unsafe { &mut *table_ptr(self.level_4_frame) };
+        let child_table = unsafe { &mut *table_ptr(child.level_4_frame) };
+        let mut cloned = Some(());
+        for (index, entry) in level_4_table.iter_mut().enumerate() {
+            if !entry.is_unused() && !is_kernel_entry(index) {
+                cloned = unsafe { clone_table(entry, &mut child_table[index], 3, frame_allocator) };
+                if cloned.is_none() {
+                    break;
+                }
+            }
+        }
+
+        // The parent lost write access to its pages, so its stale TLB entries have to go
+        if self.is_active() {
+            x86_64::instructions::tlb::flush_all();
+        } else {
+            forget_pcid(self.pcid);
+        }
+        match cloned {
+            Some(()) => Some(child),
+            None => {
+                // Dropping the child would lock the frame allocator, which the caller may hold
+                child.free(frame_allocator);
+                None
+            }
+        }
+    }
+
+    /// Frees the address space through `frame_allocator`, for callers that hold the lock of the
+    /// global frame allocator and can't let `drop` take it.
+    pub fn free(mut self, frame_allocator: &mut impl FrameDeallocator<Size4KiB>) {
+        assert!(!self.is_active(), "freeing the active address space");
+        unsafe { self.free_user_half(frame_allocator); }
+        core::mem::forget(self);
+    }
+
+    /// Frees every frame and page table in the user half.
+    unsafe fn free_user_half(&mut self, frame_allocator: &mut impl FrameDeallocator<Size4KiB>) {
+        let level_4_table = &mut *table_ptr(self.level_4_frame);
+        for (index, entry) in level_4_table.iter_mut().enumerate() {
+            if !entry.is_unused() && !is_kernel_entry(index) {
+                free_table(entry, 3, frame_allocator);
+            }
+        }
+        frame_allocator.deallocate_frame(self.level_4_frame);
+        // The frame can become the level 4 table of a new address space with the same PCID
+        forget_pcid(self.pcid);
+    }
+}
+
+/// Frees the address space through the global frame allocator. If that is locked, the frames
+/// of the address space are leaked rather than deadlocking, use `free` where that can happen.
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        match super::FRAME_ALLOCATOR.try_lock() {
+            Some(mut frame_allocator) => {
+                let frame_allocator = frame_allocator.as_mut().expect("frame allocator not initialized");
+                unsafe { self.free_user_half(frame_allocator); }
+            }
+            None => warn!("frame allocator locked, leaking address space {:?}", self.level_4_frame),
+        }
+    }
+}
+
+/// Frees the table `entry` points to at the given level, everything mapped through it, and
+/// clears the entry.
+unsafe fn free_table(entry: &mut PageTableEntry, level: u8, frame_allocator: &mut impl FrameDeallocator<Size4KiB>) {
+    let huge = entry.flags().contains(PageTableFlags::HUGE_PAGE);
+    let start = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+    if level == 0 {
+        // A mapped page, which might be shared with other address spaces
+        super::cow::free_leaf_frame(entry, frame_allocator);
+    } else if huge {
+        // A huge page, which is a contiguous run of frames
+        let frames = match level {
+            2 => Size1GiB::SIZE / Size4KiB::SIZE,
+            1 => Size2MiB::SIZE / Size4KiB::SIZE,
+            _ => 1,
+        };
+        for frame in PhysFrame::range(start, start + frames) {
+            frame_allocator.deallocate_frame(frame);
+        }
+    } else {
+        let table = &mut *table_ptr(start);
+        for child in table.iter_mut().filter(|child| !child.is_unused()) {
+            free_table(child, level - 1, frame_allocator);
+        }
+        frame_allocator.deallocate_frame(start);
+    }
+    entry.set_unused();
+}
+
+/// Copies the table `entry` points to at the given level into `child_entry`, sharing every
+/// mapped frame copy-on-write.
+unsafe fn clone_table(
+    entry: &mut PageTableEntry,
+    child_entry: &mut PageTableEntry,
+    level: u8,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Option<()> {
+    if level == 0 {
+        *child_entry = super::cow::share_entry(entry);
+        return Some(());
+    }
+    assert!(!entry.flags().contains(PageTableFlags::HUGE_PAGE), "can't share huge pages copy-on-write");
+
+    let child_frame = frame_allocator.allocate_frame()?;
+    let child_table = &mut *table_ptr(child_frame);
+    child_table.zero();
+    child_entry.set_frame(child_frame, entry.flags());
+
+    let table = &mut *table_ptr(PhysFrame::containing_address(entry.addr()));
+    for (child, child_of_copy) in table.iter_mut().zip(child_table.iter_mut()) {
+        if !child.is_unused() {
+            clone_table(child, child_of_copy, level - 1, frame_allocator)?;
+        }
+    }
+    Some(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Utility functions
+///////////////////////////////////////////////////////////////////////////////////////////////////
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    let physical_memory_offset = super::PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    (physical_memory_offset + frame.start_address().as_u64()) as *mut PageTable
+}
+
+fn p4_index(addr: u64) -> usize {
+    usize::from(VirtAddr::new(addr).p4_index())
+}
+
+fn supports_pcid() -> bool {
+    use core::arch::x86_64::__cpuid;
+    unsafe { __cpuid(1).ecx & (1 << 17) != 0 }
+}
+
+/// Loads a level 4 table into CR3. With PCIDs enabled the low bits of CR3 select the PCID. The
+/// TLB entries of the PCID are kept if they still belong to this table and no kernel mapping
+/// changed since they were loaded, otherwise they are flushed.
+unsafe fn write_cr3(frame: PhysFrame, pcid: u16) {
+    let mut value = frame.start_address().as_u64();
+    if PCID_ENABLED.load(Ordering::Relaxed) {
+        let owner = (frame.start_address().as_u64(), KERNEL_GENERATION.load(Ordering::SeqCst));
+        let previous = core::mem::replace(&mut PCID_OWNERS.lock()[usize::from(pcid)], owner);
+        value |= u64::from(pcid);
+        if previous == owner {
+            value |= CR3_NO_FLUSH;
+        }
+    }
+    llvm_asm!("mov cr3, $0" :: "r"(value) : "memory" : "intel", "volatile");
+}
+
+/// Makes the next load of `pcid` flush its TLB entries, for when they might be stale.
+fn forget_pcid(pcid: u16) {
+    PCID_OWNERS.lock()[usize::from(pcid)] = (0, 0);
+}
@@ -0,0 +1,155 @@
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr0, Cr0Flags, Cr3},
+    structures::paging::{
+        page_table::{FrameError, PageTableEntry},
+        FrameAllocator,
+        FrameDeallocator,
+        PageTable,
+        PageTableFlags,
+        PhysFrame,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+use alloc::collections::BTreeMap;
+use core::sync::atomic::Ordering;
+
+use super::fault::PageFaultError;
+
+/// Marks a page whose frame is shared between address spaces and reference counted
+pub const SHARED_FLAG: PageTableFlags = PageTableFlags::BIT_9;
+/// Marks a shared page that was writable before sharing, writing to it makes a private copy
+pub const COW_FLAG: PageTableFlags = PageTableFlags::BIT_10;
+
+lazy_static! {
+    /// Amount of mappings of every frame that is mapped more than once. Frames that are not in
+    /// here have a single owner.
+    static ref SHARED_FRAMES: spin::Mutex<BTreeMap<PhysFrame, usize>> = spin::Mutex::new(BTreeMap::new());
+}
+
+/// Checks that `protection::init` set `CR0.WP`. Without it the kernel can write to any page, so
+/// it would write straight into shared frames instead of faulting for a copy.
+pub fn init() {
+    assert!(
+        Cr0::read().contains(Cr0Flags::WRITE_PROTECT),
+        "copy-on-write needs CR0.WP, protection::init has to run first"
+    );
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Reference counting
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Turns the mapping in `entry` into a shared one and returns the entry for the new mapping.
+/// Writable pages become read-only copy-on-write pages in both mappings.
+pub fn share_entry(entry: &mut PageTableEntry) -> PageTableEntry {
+    let mut flags = entry.flags();
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags.remove(PageTableFlags::WRITABLE);
+        flags.insert(COW_FLAG);
+    }
+    flags.insert(SHARED_FLAG);
+    entry.set_flags(flags);
+
+    let frame = PhysFrame::containing_address(entry.addr());
+    *SHARED_FRAMES.lock().entry(frame).or_insert(1) += 1;
+
+    entry.clone()
+}
+
+/// Drops one mapping of a shared frame. Returns true if that was the last mapping, in which
+/// case the caller owns the frame and has to free it.
+pub fn release_frame(frame: PhysFrame) -> bool {
+    let mut shared_frames = SHARED_FRAMES.lock();
+    match shared_frames.get_mut(&frame) {
+        Some(count) if *count > 2 => {
+            *count -= 1;
+            false
+        }
+        Some(_) => {
+            // one mapping left, which now owns the frame exclusively
+            shared_frames.remove(&frame);
+            false
+        }
+        None => true,
+    }
+}
+
+/// Amount of mappings of `frame`.
+pub fn share_count(frame: PhysFrame) -> usize {
+    SHARED_FRAMES.lock().get(&frame).copied().unwrap_or(1)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Copy-on-write faults
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Resolves a write fault on a copy-on-write page in the active address space. Returns
+/// `Ok(false)` if the page is not a copy-on-write page.
+///
+/// If other mappings still share the frame, the page gets a private copy. Otherwise the page
+/// was the last one holding on to the frame, and is simply made writable again.
+pub fn handle_write_fault(addr: VirtAddr) -> Result<bool, PageFaultError> {
+    let entry = match active_entry(addr) {
+        Some(entry) if entry.flags().contains(COW_FLAG) => entry,
+        _ => return Ok(false),
+    };
+
+    let mut flags = entry.flags();
+    flags.remove(COW_FLAG | SHARED_FLAG);
+    flags.insert(PageTableFlags::WRITABLE);
+
+    let frame = PhysFrame::containing_address(entry.addr());
+    let mut shared_frames = SHARED_FRAMES.try_lock().ok_or(PageFaultError::Locked)?;
+    match shared_frames.get_mut(&frame) {
+        Some(count) => {
+            let mut frame_allocator = super::FRAME_ALLOCATOR.try_lock().ok_or(PageFaultError::Locked)?;
+            let frame_allocator = frame_allocator.as_mut().ok_or(PageFaultError::Locked)?;
+            let copy = frame_allocator.allocate_frame().ok_or(PageFaultError::OutOfMemory)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(frame_ptr(frame) as *const u8, frame_ptr(copy), 4096);
+            }
+            *count -= 1;
+            if *count == 1 {
+                shared_frames.remove(&frame);
+            }
+            entry.set_frame(copy, flags);
+        }
+        None => entry.set_flags(flags),
+    }
+    tlb::flush(addr);
+    Ok(true)
+}
+
+/// Frees a frame of a leaf entry that is being unmapped, unless other mappings still share it.
+pub unsafe fn free_leaf_frame(entry: &PageTableEntry, frame_allocator: &mut impl FrameDeallocator<Size4KiB>) {
+    let frame = PhysFrame::containing_address(entry.addr());
+    if !entry.flags().contains(SHARED_FLAG) || release_frame(frame) {
+        frame_allocator.deallocate_frame(frame);
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Utility functions
+///////////////////////////////////////////////////////////////////////////////////////////////////
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    let physical_memory_offset = super::PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    (physical_memory_offset + frame.start_address().as_u64()) as *mut u8
+}
+
+/// Returns the level 1 entry mapping `addr` in the active page table, or `None` if the
+/// address is not mapped or mapped by a huge page.
+pub(super) fn active_entry(addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let (mut frame, _) = Cr3::read();
+    let table_indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index()];
+    for &index in &table_indexes {
+        let table = unsafe { &*(frame_ptr(frame) as *const PageTable) };
+        frame = match table[index].frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) | Err(FrameError::HugeFrame) => return None,
+        };
+    }
+    let table = unsafe { &mut *(frame_ptr(frame) as *mut PageTable) };
+    let entry = &mut table[addr.p1_index()];
+    if entry.is_unused() { None } else { Some(entry) }
+}
@@ -0,0 +1,113 @@
+use x86_64::{
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+    VirtAddr,
+    PhysAddr,
+};
+
+use core::sync::atomic::Ordering;
+
+/// Physical address ranges devices can reach.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaZone {
+    /// Below 16 MiB, for ISA DMA
+    Isa,
+    /// Below 4 GiB, for devices with 32 bit addressing
+    Dma32,
+    /// Anywhere in physical memory
+    Any,
+}
+
+impl DmaZone {
+    /// The first physical address that lies outside of the zone.
+    pub fn limit(self) -> PhysAddr {
+        match self {
+            DmaZone::Isa => PhysAddr::new(16 * 1024 * 1024),
+            DmaZone::Dma32 => PhysAddr::new(4 * 1024 * 1024 * 1024),
+            DmaZone::Any => PhysAddr::new(u64::MAX),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// The size is zero
+    EmptyBuffer,
+    /// The alignment is not a power of two
+    InvalidAlignment,
+    /// No free contiguous run of frames large enough in the zone
+    OutOfMemory,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// DMA buffers
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A zeroed, physically contiguous buffer that devices can read and write. The frames go back
+/// to the frame allocator when the buffer is dropped.
+///
+/// The buffer is accessed through the physical memory mapping, which is cached. That is fine
+/// for DMA on x86, where device accesses are coherent with the caches.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    start: PhysFrame,
+    frames: usize,
+    size: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates a buffer of at least `size` bytes, with its physical address aligned to `align`
+    /// bytes, that lies completely inside `zone`. The buffer always starts on a frame boundary.
+    pub fn allocate(size: usize, align: usize, zone: DmaZone) -> Result<Self, DmaError> {
+        if size == 0 {
+            return Err(DmaError::EmptyBuffer);
+        }
+        if !align.is_power_of_two() {
+            return Err(DmaError::InvalidAlignment);
+        }
+        let frame_size = Size4KiB::SIZE as usize;
+        let frames = (size + frame_size - 1) / frame_size;
+        let align_frames = core::cmp::max(align, frame_size) / frame_size;
+
+        let start = {
+            let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
+            let frame_allocator = frame_allocator.as_mut().expect("frame allocator not initialized");
+            frame_allocator
+                .allocate_contiguous_below(frames, align_frames, zone.limit())
+                .ok_or(DmaError::OutOfMemory)?
+        };
+
+        let buffer = Self { start, frames, size };
+        unsafe { core::ptr::write_bytes(buffer.virt_addr().as_mut_ptr::<u8>(), 0, frames * frame_size); }
+        Ok(buffer)
+    }
+
+    /// The address to hand to the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.start.start_address()
+    }
+
+    /// The address the kernel accesses the buffer at.
+    pub fn virt_addr(&self) -> VirtAddr {
+        let physical_memory_offset = super::PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+        VirtAddr::new(physical_memory_offset + self.phys_addr().as_u64())
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt_addr().as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr(), self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().expect("frame allocator not initialized");
+        unsafe { frame_allocator.deallocate_contiguous(self.start, self.frames); }
+    }
+}
@@ -0,0 +1,78 @@
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB},
+    },
+    VirtAddr,
+};
+
+use super::vma::{self, VirtualRegion};
+
+/// Reasons a page fault could not be resolved.
+#[derive(Debug, Clone, Copy)]
+pub enum PageFaultError {
+    /// The address does not belong to any region
+    NoRegion,
+    /// The address lies in the guard pages below a region, usually a stack overflow
+    GuardPage(VirtualRegion),
+    /// The page is present, but the access is not allowed
+    ProtectionViolation(VirtualRegion),
+    /// The page is not present and the region is not lazily backed
+    NotBacked(VirtualRegion),
+    /// The mapper, frame allocator or region manager was locked by the interrupted code
+    Locked,
+    OutOfMemory,
+}
+
+/// Tries to resolve a page fault at `addr`. Write faults on copy-on-write pages get a private
+/// copy of the page, and not-present faults in lazy regions get a zeroed frame mapped at the
+/// faulting page. After that the faulting instruction can be retried.
+///
+/// This only uses `try_lock`, since the fault might have happened while the interrupted code
+/// was holding one of the locks.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), PageFaultError> {
+    let write_violation = PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION;
+    if error_code.contains(write_violation) && super::cow::handle_write_fault(addr)? {
+        return Ok(());
+    }
+
+    let region = vma::KERNEL_VMA
+        .try_lock()
+        .ok_or(PageFaultError::Locked)?
+        .find(addr)
+        .ok_or(PageFaultError::NoRegion)?;
+
+    if region.guard_contains(addr) {
+        return Err(PageFaultError::GuardPage(region));
+    }
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(PageFaultError::ProtectionViolation(region));
+    }
+    if !region.is_lazy() {
+        return Err(PageFaultError::NotBacked(region));
+    }
+
+    map_zeroed_page(Page::containing_address(addr))?;
+    if region.kind() == vma::RegionKind::Stack {
+        super::stats::stack_frames_mapped(1);
+    }
+    Ok(())
+}
+
+fn map_zeroed_page(page: Page<Size4KiB>) -> Result<(), PageFaultError> {
+    let mut mapper = super::MAPPER.try_lock().ok_or(PageFaultError::Locked)?;
+    let mut frame_allocator = super::FRAME_ALLOCATOR.try_lock().ok_or(PageFaultError::Locked)?;
+    let mapper = mapper.as_mut().ok_or(PageFaultError::Locked)?;
+    let frame_allocator = frame_allocator.as_mut().ok_or(PageFaultError::Locked)?;
+
+    let frame = frame_allocator.allocate_frame().ok_or(PageFaultError::OutOfMemory)?;
+    let physical_memory_offset = super::PHYSICAL_MEMORY_OFFSET.load(core::sync::atomic::Ordering::Relaxed);
+    let frame_ptr = (physical_memory_offset + frame.start_address().as_u64()) as *mut u8;
+    unsafe { core::ptr::write_bytes(frame_ptr, 0, Page::<Size4KiB>::SIZE as usize); }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | super::protection::no_execute();
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+        .map_err(|_| PageFaultError::OutOfMemory)?
+        .flush();
+    Ok(())
+}
@@ -0,0 +1,223 @@
+use x86_64::{
+    structures::paging::{
+        PhysFrame,
+        Size4KiB,
+        FrameAllocator,
+        FrameDeallocator,
+    },
+    VirtAddr,
+    PhysAddr,
+};
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Bitmap frame allocator
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A physical frame allocator backed by a two level bitmap.
+///
+/// Every frame below the highest usable address gets one bit in `bitmap` (set = in use).
+/// Every word of `bitmap` gets one bit in `summary` (set = word has at least one free frame),
+/// so finding a free frame only has to look at a handful of summary words instead of walking
+/// the whole memory map. The bitmap itself lives in the first usable region that is large
+/// enough to hold it, and is accessed through the physical memory mapping.
+pub struct BitmapFrameAllocator {
+    memory_map: &'static MemoryMap,
+    bitmap: &'static mut [u64],
+    summary: &'static mut [u64],
+    frame_count: usize,
+    usable_frames: usize,
+    free_frames: usize,
+    next_summary: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid and that the complete physical memory is mapped at
+    /// `physical_memory_offset`. The main requirement is that all frames that are marked
+    /// as `USABLE` in it are really unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_addr() / FRAME_SIZE)
+            .max()
+            .unwrap_or(0) as usize;
+        let bitmap_words = div_ceil(frame_count, BITS_PER_WORD);
+        let summary_words = div_ceil(bitmap_words, BITS_PER_WORD);
+        let storage_frames = div_ceil((bitmap_words + summary_words) * 8, FRAME_SIZE as usize) as u64;
+
+        // Steal the start of the first usable region that can hold the bitmap
+        let storage_start = usable_regions()
+            .find(|r| (r.range.end_addr() - r.range.start_addr()) / FRAME_SIZE >= storage_frames)
+            .expect("no usable region large enough for the frame bitmap")
+            .range.start_addr();
+
+        let storage_ptr: *mut u64 = (physical_memory_offset + storage_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(storage_ptr, bitmap_words);
+        let summary = core::slice::from_raw_parts_mut(storage_ptr.add(bitmap_words), summary_words);
+
+        let mut allocator = BitmapFrameAllocator {
+            memory_map,
+            bitmap,
+            summary,
+            frame_count,
+            usable_frames: 0,
+            free_frames: 0,
+            next_summary: 0,
+        };
+
+        // Everything starts out used, then the usable regions are released
+        for word in allocator.bitmap.iter_mut() { *word = !0; }
+        for word in allocator.summary.iter_mut() { *word = 0; }
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for index in start..end {
+                allocator.set_free(index);
+            }
+            allocator.usable_frames += end - start;
+        }
+
+        // The frames holding the bitmap are not free
+        let storage_index = (storage_start / FRAME_SIZE) as usize;
+        for index in storage_index..storage_index + storage_frames as usize {
+            allocator.set_used(index);
+        }
+
+        allocator
+    }
+
+    /// The memory map this allocator was created from.
+    pub fn memory_map(&self) -> &'static MemoryMap {
+        self.memory_map
+    }
+
+    /// Amount of frames marked as usable by the bootloader.
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Amount of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Allocates `count` physically contiguous frames, with the first frame aligned to
+    /// `align` frames. Returns the first frame of the run.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        self.allocate_contiguous_below(count, align, PhysAddr::new(u64::MAX))
+    }
+
+    /// Like `allocate_contiguous`, but the whole run lies below `limit`, for devices that
+    /// can't reach all of physical memory.
+    pub fn allocate_contiguous_below(&mut self, count: usize, align: usize, limit: PhysAddr) -> Option<PhysFrame> {
+        assert!(count > 0, "cannot allocate 0 frames");
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+
+        let end = core::cmp::min(self.frame_count as u64, limit.as_u64() / FRAME_SIZE) as usize;
+        let mut start = 0;
+        while start + count <= end {
+            match (start..start + count).rev().find(|&index| self.is_used(index)) {
+                // Skip past the used frame, rounded up to the next aligned index
+                Some(used) => start = (used + align) & !(align - 1),
+                None => {
+                    for index in start..start + count {
+                        self.set_used(index);
+                    }
+                    return Some(frame_from_index(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns `count` contiguous frames starting at `start` to the allocator.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frames were
+    /// allocated by this allocator and are no longer in use.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = index_from_frame(start);
+        for index in first..first + count {
+            self.release(index);
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_used(&mut self, index: usize) {
+        let word = index / BITS_PER_WORD;
+        let bit = 1 << (index % BITS_PER_WORD);
+        if self.bitmap[word] & bit == 0 {
+            self.bitmap[word] |= bit;
+            self.free_frames -= 1;
+            if self.bitmap[word] == !0 {
+                self.summary[word / BITS_PER_WORD] &= !(1 << (word % BITS_PER_WORD));
+            }
+        }
+    }
+
+    fn set_free(&mut self, index: usize) {
+        let word = index / BITS_PER_WORD;
+        let bit = 1 << (index % BITS_PER_WORD);
+        if self.bitmap[word] & bit != 0 {
+            self.bitmap[word] &= !bit;
+            self.free_frames += 1;
+            self.summary[word / BITS_PER_WORD] |= 1 << (word % BITS_PER_WORD);
+        }
+    }
+
+    fn release(&mut self, index: usize) {
+        assert!(index < self.frame_count, "frame {:#x} is not managed by the allocator", index as u64 * FRAME_SIZE);
+        assert!(self.is_used(index), "double free of frame {:#x}", index as u64 * FRAME_SIZE);
+        self.set_free(index);
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let summary_words = self.summary.len();
+        for i in 0..summary_words {
+            let summary_index = (self.next_summary + i) % summary_words;
+            let summary = self.summary[summary_index];
+            if summary == 0 {
+                continue;
+            }
+            let word = summary_index * BITS_PER_WORD + summary.trailing_zeros() as usize;
+            let index = word * BITS_PER_WORD + (!self.bitmap[word]).trailing_zeros() as usize;
+            self.set_used(index);
+            self.next_summary = summary_index;
+            return Some(frame_from_index(index));
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.release(index_from_frame(frame));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Utility functions
+///////////////////////////////////////////////////////////////////////////////////////////////////
+fn div_ceil(value: usize, divisor: usize) -> usize {
+    (value + divisor - 1) / divisor
+}
+
+fn frame_from_index(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+fn index_from_frame(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
@@ -0,0 +1,231 @@
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, TranslateResult, UnmapError},
+        FrameAllocator,
+        Mapper,
+        MapperAllSizes,
+        Page,
+        PageSize,
+        PageTableFlags,
+        PhysFrame,
+        Size4KiB,
+        Size2MiB,
+        Size1GiB,
+    },
+    VirtAddr,
+    PhysAddr,
+};
+
+use super::{address_space, BitmapFrameAllocator};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapRangeError {
+    /// The start addresses or size are not 4 KiB aligned
+    NotAligned,
+    FrameAllocationFailed,
+    ParentEntryHugePage,
+    PageAlreadyMapped,
+}
+
+impl<S: PageSize> From<MapToError<S>> for MapRangeError {
+    fn from(error: MapToError<S>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => MapRangeError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MapRangeError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(_) => MapRangeError::PageAlreadyMapped,
+        }
+    }
+}
+
+/// Returns true if the CPU can map 1 GiB pages.
+pub fn supports_1gib_pages() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    unsafe {
+        __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0
+    }
+}
+
+/// Returns the largest page size that can map the start of the range, given the alignment
+/// of both addresses and the remaining size.
+fn largest_page_size(virt: VirtAddr, phys: PhysAddr, remaining: u64, allow_1gib: bool) -> u64 {
+    let fits = |size: u64| {
+        virt.is_aligned(size) && phys.is_aligned(size) && remaining >= size
+    };
+    if allow_1gib && fits(Size1GiB::SIZE) {
+        Size1GiB::SIZE
+    } else if fits(Size2MiB::SIZE) {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Range mapping
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Maps `size` bytes of physical memory starting at `phys_start` to `virt_start`, using
+/// 1 GiB and 2 MiB pages wherever the alignment of both addresses allows it.
+///
+/// This function is unsafe because the caller must guarantee that the physical range is
+/// valid to be mapped with the given flags, and that nothing else uses the virtual range.
+pub unsafe fn map_range(
+    virt_start: VirtAddr,
+    phys_start: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapRangeError> {
+    if !virt_start.is_aligned(Size4KiB::SIZE) || !phys_start.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+        return Err(MapRangeError::NotAligned);
+    }
+    let allow_1gib = supports_1gib_pages();
+
+    let mut offset = 0;
+    while offset < size {
+        let virt = virt_start + offset;
+        let phys = phys_start + offset;
+        let page_size = largest_page_size(virt, phys, size - offset, allow_1gib);
+        map_page(virt, phys, page_size, flags, mapper, frame_allocator)?;
+        offset += page_size;
+    }
+    Ok(())
+}
+
+/// Maps `size` bytes of fresh memory at `virt_start`. Physically contiguous 2 MiB runs are
+/// taken from the frame allocator where the virtual alignment allows it, so large regions
+/// end up mostly mapped with 2 MiB pages.
+///
+/// If mapping fails partway, the pages mapped so far are unmapped and freed again, so the
+/// range can be mapped later.
+pub fn alloc_and_map_range(
+    virt_start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), MapRangeError> {
+    if !virt_start.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+        return Err(MapRangeError::NotAligned);
+    }
+    let frames_per_2mib = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+
+    let mut offset = 0;
+    while offset < size {
+        let virt = virt_start + offset;
+        let huge_frame = if virt.is_aligned(Size2MiB::SIZE) && size - offset >= Size2MiB::SIZE {
+            frame_allocator.allocate_contiguous(frames_per_2mib, frames_per_2mib)
+        } else {
+            None
+        };
+        let (phys, page_size) = match huge_frame {
+            Some(frame) => (frame.start_address(), Size2MiB::SIZE),
+            None => match frame_allocator.allocate_frame() {
+                Some(frame) => (frame.start_address(), Size4KiB::SIZE),
+                None => {
+                    unsafe { unmap_and_free_range(virt_start, offset, mapper, frame_allocator).expect("failed to roll back mapping"); }
+                    return Err(MapRangeError::FrameAllocationFailed);
+                }
+            },
+        };
+        if let Err(err) = unsafe { map_page(virt, phys, page_size, flags, mapper, frame_allocator) } {
+            unsafe {
+                frame_allocator.deallocate_contiguous(PhysFrame::containing_address(phys), (page_size / Size4KiB::SIZE) as usize);
+                unmap_and_free_range(virt_start, offset, mapper, frame_allocator).expect("failed to roll back mapping");
+            }
+            return Err(err);
+        }
+        offset += page_size;
+    }
+    Ok(())
+}
+
+unsafe fn map_page(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    page_size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapRangeError> {
+    match page_size {
+        Size1GiB::SIZE => {
+            let page = Page::<Size1GiB>::containing_address(virt);
+            let frame = PhysFrame::<Size1GiB>::containing_address(phys);
+            Mapper::<Size1GiB>::map_to(mapper, page, frame, flags, frame_allocator)?.flush();
+        }
+        Size2MiB::SIZE => {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::<Size2MiB>::containing_address(phys);
+            Mapper::<Size2MiB>::map_to(mapper, page, frame, flags, frame_allocator)?.flush();
+        }
+        _ => {
+            let page = Page::<Size4KiB>::containing_address(virt);
+            let frame = PhysFrame::<Size4KiB>::containing_address(phys);
+            Mapper::<Size4KiB>::map_to(mapper, page, frame, flags, frame_allocator)?.flush();
+        }
+    }
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Range unmapping
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Unmaps every page in the given range, whatever its size. Huge pages that only partially
+/// overlap the range are unmapped as a whole. Unmapped holes in the range are skipped.
+///
+/// `on_unmap` is called with the physical start address and size of every unmapped page.
+/// Other PCIDs are flushed when they are loaded next, see `address_space::kernel_mapping_changed`.
+pub fn unmap_range(
+    virt_start: VirtAddr,
+    size: u64,
+    mapper: &mut impl MapperAllSizes,
+    mut on_unmap: impl FnMut(PhysAddr, u64),
+) -> Result<(), UnmapError> {
+    let virt_end = virt_start + size;
+    let mut virt = virt_start;
+    while virt < virt_end {
+        let (phys, page_size) = match mapper.translate(virt) {
+            TranslateResult::Frame4KiB { .. } => {
+                let (frame, flush) = Mapper::<Size4KiB>::unmap(mapper, Page::containing_address(virt))?;
+                flush.flush();
+                (frame.start_address(), Size4KiB::SIZE)
+            }
+            TranslateResult::Frame2MiB { .. } => {
+                let (frame, flush) = Mapper::<Size2MiB>::unmap(mapper, Page::containing_address(virt))?;
+                flush.flush();
+                (frame.start_address(), Size2MiB::SIZE)
+            }
+            TranslateResult::Frame1GiB { .. } => {
+                let (frame, flush) = Mapper::<Size1GiB>::unmap(mapper, Page::containing_address(virt))?;
+                flush.flush();
+                (frame.start_address(), Size1GiB::SIZE)
+            }
+            TranslateResult::PageNotMapped => {
+                virt = VirtAddr::new(virt.as_u64() & !(Size4KiB::SIZE - 1)) + Size4KiB::SIZE;
+                continue;
+            }
+            TranslateResult::InvalidFrameAddress(addr) => return Err(UnmapError::InvalidFrameAddress(addr)),
+        };
+        address_space::kernel_mapping_changed();
+        on_unmap(phys, page_size);
+        virt = VirtAddr::new(virt.as_u64() & !(page_size - 1)) + page_size;
+    }
+    Ok(())
+}
+
+/// Unmaps a range mapped by `alloc_and_map_range` and returns its frames to the allocator.
+///
+/// This function is unsafe because the caller must guarantee that the memory is no longer used.
+pub unsafe fn unmap_and_free_range(
+    virt_start: VirtAddr,
+    size: u64,
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), UnmapError> {
+    unmap_range(virt_start, size, mapper, |phys, page_size| {
+        let frame = PhysFrame::containing_address(phys);
+        frame_allocator.deallocate_contiguous(frame, (page_size / Size4KiB::SIZE) as usize);
+    })
+}
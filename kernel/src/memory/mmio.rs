@@ -0,0 +1,182 @@
+use x86_64::{
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+    PhysAddr,
+};
+
+use core::mem::size_of;
+
+use super::mapping::{self, MapRangeError};
+use super::vma::{self, RegionKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioError {
+    /// The size of the range is zero
+    EmptyRange,
+    /// No free range left in the kernel virtual address space
+    OutOfVirtualMemory,
+    Map(MapRangeError),
+}
+
+impl From<MapRangeError> for MmioError {
+    fn from(error: MapRangeError) -> Self {
+        MmioError::Map(error)
+    }
+}
+
+/// A value that can be read from and written to device memory in a single access.
+pub trait MmioValue: Copy {}
+
+impl MmioValue for u8 {}
+impl MmioValue for u16 {}
+impl MmioValue for u32 {}
+impl MmioValue for u64 {}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Mapping
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A range of device memory mapped into the kernel virtual address space. The pages are mapped
+/// with caching disabled, so every read and write reaches the device, and are unmapped again
+/// when the mapping is dropped.
+///
+/// Offsets passed to the accessors are relative to the physical address the range was mapped
+/// from, and must be naturally aligned for the accessed type.
+#[derive(Debug)]
+pub struct Mmio {
+    phys_start: PhysAddr,
+    /// Virtual address of the first mapped page, which might start before `phys_start`
+    virt_page: VirtAddr,
+    /// Offset of `phys_start` into the first mapped page
+    page_offset: u64,
+    size: u64,
+}
+
+impl Mmio {
+    /// Maps `size` bytes of device memory starting at `phys_start`. The start address does not
+    /// have to be page aligned, the pages around the range are mapped as well in that case.
+    ///
+    /// This function is unsafe because the caller must guarantee that the physical range belongs
+    /// to a device and is not used as regular memory.
+    pub unsafe fn map(phys_start: PhysAddr, size: u64, name: &'static str) -> Result<Self, MmioError> {
+        if size == 0 {
+            return Err(MmioError::EmptyRange);
+        }
+        let page_offset = phys_start.as_u64() & (Size4KiB::SIZE - 1);
+        let phys_page = phys_start.align_down(Size4KiB::SIZE);
+        let pages = (page_offset + size + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+
+        let region = vma::allocate(pages, 1, RegionKind::Mmio, name).ok_or(MmioError::OutOfVirtualMemory)?;
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH
+            | super::protection::no_execute();
+        let mapped = {
+            let mut mapper = super::MAPPER.lock();
+            let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
+            mapping::map_range(
+                region.start(),
+                phys_page,
+                pages * Size4KiB::SIZE,
+                flags,
+                mapper.as_mut().expect("mapper not initialized"),
+                frame_allocator.as_mut().expect("frame allocator not initialized"),
+            )
+        };
+        if let Err(err) = mapped {
+            vma::free(region.start());
+            return Err(err.into());
+        }
+
+        Ok(Self {
+            phys_start,
+            virt_page: region.start(),
+            page_offset,
+            size,
+        })
+    }
+
+    pub fn phys_start(&self) -> PhysAddr {
+        self.phys_start
+    }
+
+    pub fn virt_start(&self) -> VirtAddr {
+        self.virt_page + self.page_offset
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Reads a value at `offset` bytes into the range with a single volatile access.
+    pub fn read<T: MmioValue>(&self, offset: u64) -> T {
+        unsafe { core::ptr::read_volatile(self.ptr::<T>(offset)) }
+    }
+
+    /// Writes a value at `offset` bytes into the range with a single volatile access.
+    pub fn write<T: MmioValue>(&self, offset: u64, value: T) {
+        unsafe { core::ptr::write_volatile(self.ptr::<T>(offset), value) }
+    }
+
+    pub fn read_u8(&self, offset: u64) -> u8 {
+        self.read(offset)
+    }
+
+    pub fn read_u16(&self, offset: u64) -> u16 {
+        self.read(offset)
+    }
+
+    pub fn read_u32(&self, offset: u64) -> u32 {
+        self.read(offset)
+    }
+
+    pub fn read_u64(&self, offset: u64) -> u64 {
+        self.read(offset)
+    }
+
+    pub fn write_u8(&self, offset: u64, value: u8) {
+        self.write(offset, value)
+    }
+
+    pub fn write_u16(&self, offset: u64, value: u16) {
+        self.write(offset, value)
+    }
+
+    pub fn write_u32(&self, offset: u64, value: u32) {
+        self.write(offset, value)
+    }
+
+    pub fn write_u64(&self, offset: u64, value: u64) {
+        self.write(offset, value)
+    }
+
+    /// Returns a pointer to a `T` at `offset`, after checking that it lies in the range and is
+    /// naturally aligned. Unaligned device accesses are split or rejected by most hardware.
+    fn ptr<T>(&self, offset: u64) -> *mut T {
+        let access_size = size_of::<T>() as u64;
+        assert!(
+            offset.checked_add(access_size).map_or(false, |end| end <= self.size),
+            "MMIO access of {} bytes at offset {:#x} is out of range (size {:#x})", access_size, offset, self.size
+        );
+        let phys = self.phys_start + offset;
+        assert!(
+            phys.is_aligned(access_size),
+            "MMIO access of {} bytes at {:?} is not aligned", access_size, phys
+        );
+        (self.virt_start() + offset).as_mut_ptr()
+    }
+}
+
+impl Drop for Mmio {
+    fn drop(&mut self) {
+        let mapped_size = (self.page_offset + self.size + Size4KiB::SIZE - 1) / Size4KiB::SIZE * Size4KiB::SIZE;
+        {
+            let mut mapper = super::MAPPER.lock();
+            let mapper = mapper.as_mut().expect("mapper not initialized");
+            // The frames belong to the device, so there is nothing to free
+            mapping::unmap_range(self.virt_page, mapped_size, mapper, |_, _| {})
+                .expect("failed to unmap MMIO range");
+        }
+        vma::free(self.virt_page).expect("MMIO range has no virtual region");
+    }
+}
@@ -0,0 +1,273 @@
+use x86_64::{
+    structures::paging::{
+        mapper,
+        PageTable,
+        OffsetPageTable,
+        Page,
+        PhysFrame,
+        Mapper,
+        PageSize,
+        Size4KiB,
+        Size2MiB,
+        Size1GiB,
+        FrameAllocator,
+        FrameDeallocator,
+    },
+    VirtAddr,
+    PhysAddr,
+};
+
+pub mod address_space;
+pub mod cow;
+pub mod dma;
+pub mod fault;
+pub mod frame_allocator;
+pub mod mapping;
+pub mod mmio;
+pub mod protection;
+pub mod stats;
+pub mod vma;
+
+pub use dma::{DmaBuffer, DmaZone};
+pub use frame_allocator::BitmapFrameAllocator;
+pub use mmio::Mmio;
+pub use stats::{stats, MemoryStats};
+
+/// Initialize a new OffsetPageTable.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+/// Returns a mutable reference to the active level 4 table.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
+    -> &'static mut PageTable
+{
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr // unsafe
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Stack allocation
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackBounds {
+    start: VirtAddr,
+    end: VirtAddr,
+}
+
+impl StackBounds {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+}
+
+/// Amount of pages at the top of a lazy stack that are mapped right away. The rest of the stack
+/// is backed on demand by the page fault handler as the stack grows down.
+const STACK_EAGER_PAGES: u64 = 1;
+
+#[derive(Debug)]
+pub enum StackError {
+    /// No free range left in the kernel virtual address space
+    OutOfVirtualMemory,
+    Map(mapper::MapToError<Size4KiB>),
+}
+
+impl From<mapper::MapToError<Size4KiB>> for StackError {
+    fn from(error: mapper::MapToError<Size4KiB>) -> Self {
+        StackError::Map(error)
+    }
+}
+
+/// Allocates a stack with every page mapped. Kernel thread stacks have to be allocated like this:
+/// the page fault handler can't back a page while the faulting code holds the scheduler, mapper,
+/// frame allocator or region manager lock, and kernel threads run code under all of them.
+pub fn alloc_stack(
+    size_in_pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<StackBounds, StackError> {
+    let region = vma::allocate(size_in_pages, 1, vma::RegionKind::Stack, "thread stack")
+        .ok_or(StackError::OutOfVirtualMemory)?;
+    map_stack(region.start(), size_in_pages, size_in_pages, mapper, frame_allocator)
+}
+
+/// Allocates a stack that only has its top pages mapped and grows through the page fault
+/// handler. Only for stacks that never run code under the locks listed at `alloc_stack`.
+pub fn alloc_lazy_stack(
+    size_in_pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<StackBounds, StackError> {
+    let region = vma::allocate_lazy(size_in_pages, 1, vma::RegionKind::Stack, "lazy stack")
+        .ok_or(StackError::OutOfVirtualMemory)?;
+    let eager_pages = core::cmp::min(STACK_EAGER_PAGES, size_in_pages);
+    map_stack(region.start(), size_in_pages, eager_pages, mapper, frame_allocator)
+}
+
+/// Maps the top `eager_pages` of the stack at `start`, and frees the stack again on failure.
+fn map_stack(
+    start: VirtAddr,
+    size_in_pages: u64,
+    eager_pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<StackBounds, StackError> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let stack_start = Page::containing_address(start);
+    let stack_end = stack_start + size_in_pages;
+    let eager_start = stack_end - eager_pages;
+    let flags = Flags::PRESENT | Flags::WRITABLE | protection::no_execute();
+    for page in Page::range(eager_start, stack_end) {
+        let mapped = match frame_allocator.allocate_frame() {
+            Some(frame) => unsafe {
+                mapper.map_to(page, frame, flags, frame_allocator)
+                    .map(|flush| flush.flush())
+                    .map_err(|err| {
+                        frame_allocator.deallocate_frame(frame);
+                        err
+                    })
+            },
+            None => Err(mapper::MapToError::FrameAllocationFailed),
+        };
+        if let Err(err) = mapped {
+            let bounds = StackBounds { start: stack_start.start_address(), end: stack_end.start_address() };
+            // Unmaps the pages mapped so far and releases the region
+            unsafe { free_stack(bounds, mapper, frame_allocator).expect("failed to free stack"); }
+            return Err(err.into());
+        }
+        stats::stack_frames_mapped(1);
+    }
+    Ok(StackBounds {
+        start: stack_start.start_address(),
+        end: stack_end.start_address(),
+    })
+}
+
+/// Unmaps the pages of a stack allocated by `alloc_stack`, returns its frames to the frame
+/// allocator and makes the virtual range (including the guard page) available again.
+///
+/// This function is unsafe because the caller must guarantee that the stack is no longer
+/// in use, which means it can't be called from the thread running on it.
+pub unsafe fn free_stack(
+    stack_bounds: StackBounds,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> Result<(), mapper::UnmapError> {
+    let stack_start = Page::<Size4KiB>::containing_address(stack_bounds.start);
+    let stack_end = Page::<Size4KiB>::containing_address(stack_bounds.end);
+    for page in Page::range(stack_start, stack_end) {
+        let (frame, flush) = match mapper.unmap(page) {
+            Ok(unmapped) => unmapped,
+            // never touched, so the page fault handler did not back it
+            Err(mapper::UnmapError::PageNotMapped) => continue,
+            Err(err) => return Err(err),
+        };
+        flush.flush();
+        address_space::kernel_mapping_changed();
+        frame_allocator.deallocate_frame(frame);
+        stats::stack_frames_freed(1);
+    }
+    vma::free(stack_bounds.start).expect("stack has no virtual region");
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Address translation
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Translates the given virtual address to the mapped physical address, or
+/// `None` if the address is not mapped.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`.
+pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<PhysAddr>
+{
+    translate_addr_inner(addr, physical_memory_offset)
+}
+
+/// Private function that is called by `translate_addr`.
+///
+/// This function is safe to limit the scope of `unsafe` because Rust treats
+/// the whole body of unsafe functions as an unsafe block. This function must
+/// only be reachable through `unsafe fn` from outside of this module.
+fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<PhysAddr>
+{
+    use x86_64::structures::paging::page_table::FrameError;
+    use x86_64::registers::control::Cr3;
+
+    // read the active level 4 frame from the CR3 register
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let table_indexes = [
+        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
+    ];
+    // size of the page an entry maps at each level if it is a huge page, level 4 entries can't be
+    let page_sizes = [None, Some(Size1GiB::SIZE), Some(Size2MiB::SIZE), Some(Size4KiB::SIZE)];
+    let mut frame = level_4_table_frame;
+
+    // traverse the multi-level page table
+    for (&index, &page_size) in table_indexes.iter().zip(page_sizes.iter()) {
+        // convert the frame into a page table reference
+        let virt = physical_memory_offset + frame.start_address().as_u64();
+        let table_ptr: *const PageTable = virt.as_ptr();
+        let table = unsafe {&*table_ptr};
+
+        // read the page table entry and update `frame`
+        let entry = &table[index];
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return None,
+            Err(FrameError::HugeFrame) => {
+                // a huge page ends the walk, the rest of the address is the offset into it
+                let page_size = page_size?;
+                // bit 12 of a huge page entry is the PAT bit, not part of the address
+                return Some(entry.addr().align_down(page_size) + (addr.as_u64() & (page_size - 1)));
+            }
+        };
+    }
+
+    // calculate the physical address by adding the page offset
+    Some(frame.start_address() + u64::from(addr.page_offset()))
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Singleton mapper, frame allocator and physical memory offset
+///////////////////////////////////////////////////////////////////////////////////////////////////
+lazy_static! {
+    pub static ref MAPPER: spin::Mutex<Option<OffsetPageTable<'static>>> = spin::Mutex::new(None);
+}
+
+lazy_static! {
+    pub static ref FRAME_ALLOCATOR: spin::Mutex<Option<BitmapFrameAllocator>> = spin::Mutex::new(None);
+}
+
+use core::sync::atomic::{AtomicU64, Ordering};
+pub static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+pub fn update_physical_memory_offset(phys_mem_offset: u64) {
+    PHYSICAL_MEMORY_OFFSET.store(phys_mem_offset, Ordering::Relaxed);
+}
@@ -0,0 +1,169 @@
+use x86_64::{
+    instructions::tlb,
+    registers::{
+        control::{Cr0, Cr0Flags, Cr3},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{Page, PageTable, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    // Defined by the linker: the ELF header at the start of the image
+    static __ehdr_start: u8;
+}
+
+/// Program header type of a loadable segment
+const PT_LOAD: u32 = 1;
+/// Program header flags of a segment
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Control registers
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Enables no-execute pages and makes read-only pages read-only for the kernel too. Without
+/// `CR0.WP` the kernel could still write to its own code and read-only data.
+pub fn init() {
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)); }
+    if supports_nx() {
+        unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)); }
+        NX_ENABLED.store(true, Ordering::SeqCst);
+    } else {
+        warn!("CPU does not support no-execute pages, data mappings stay executable");
+    }
+}
+
+/// Returns `NO_EXECUTE` if no-execute pages are enabled, and no flags otherwise. The bit is
+/// reserved without `EFER.NXE`, so setting it anyway would make every access to the page fault.
+pub fn no_execute() -> PageTableFlags {
+    if NX_ENABLED.load(Ordering::Relaxed) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+fn supports_nx() -> bool {
+    use core::arch::x86_64::__cpuid;
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 20) != 0 }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Kernel mappings
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Makes sure no kernel page is both writable and executable.
+///
+/// Every page of the kernel image gets the permissions of the ELF segment it belongs to: code
+/// becomes read-only and executable, read-only data read-only and non-executable, and data and
+/// bss non-executable. The physical memory mapping only ever holds data, so it becomes
+/// non-executable as a whole.
+pub fn enforce_w_xor_x(physical_memory_size: u64) {
+    if !NX_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    protect_kernel_image();
+    protect_physical_memory_mapping(physical_memory_size);
+}
+
+fn protect_kernel_image() {
+    // Executable segments go last, so a page shared with a data segment stays executable
+    for executable in [false, true].iter().copied() {
+        for (flags, start, size) in loadable_segments() {
+            if (flags & PF_X != 0) == executable && size != 0 {
+                protect_segment(flags, start, size);
+            }
+        }
+    }
+}
+
+fn protect_segment(segment_flags: u32, start: VirtAddr, size: u64) {
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (size - 1));
+    for page in Page::range_inclusive(first, last) {
+        let entry = match super::cow::active_entry(page.start_address()) {
+            Some(entry) => entry,
+            None => continue, // not mapped, or part of a huge page
+        };
+        let flags = entry.flags();
+        let mut new_flags = flags;
+        if segment_flags & PF_X != 0 {
+            new_flags.remove(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE);
+        } else {
+            new_flags.insert(PageTableFlags::NO_EXECUTE);
+            if segment_flags & PF_W == 0 {
+                new_flags.remove(PageTableFlags::WRITABLE);
+            }
+        }
+        if new_flags != flags {
+            entry.set_flags(new_flags);
+            tlb::flush(page.start_address());
+        }
+    }
+}
+
+/// Returns the flags, start address and memory size of every loadable segment of the kernel,
+/// read from the program headers that the linker maps along with the ELF header.
+fn loadable_segments() -> impl Iterator<Item = (u32, VirtAddr, u64)> {
+    let header = unsafe { &__ehdr_start as *const u8 };
+    // Offsets of e_phoff, e_phentsize and e_phnum in the ELF64 header
+    let (program_headers, entry_size, count) = unsafe {
+        (
+            header.add(read::<u64>(header, 32) as usize),
+            read::<u16>(header, 54) as usize,
+            read::<u16>(header, 56) as usize,
+        )
+    };
+    (0..count).filter_map(move |index| {
+        let program_header = unsafe { program_headers.add(index * entry_size) };
+        // Offsets of p_type, p_flags, p_vaddr and p_memsz in the ELF64 program header
+        let (kind, flags, start, size) = unsafe {
+            (
+                read::<u32>(program_header, 0),
+                read::<u32>(program_header, 4),
+                read::<u64>(program_header, 16),
+                read::<u64>(program_header, 40),
+            )
+        };
+        if kind == PT_LOAD { Some((flags, VirtAddr::new(start), size)) } else { None }
+    })
+}
+
+unsafe fn read<T: Copy>(base: *const u8, offset: usize) -> T {
+    core::ptr::read_unaligned(base.add(offset) as *const T)
+}
+
+/// Returns the flags of the page mapping `addr` in the active page table, or `None` if the
+/// address is not mapped or mapped by a huge page.
+pub fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    super::cow::active_entry(addr).map(|entry| entry.flags())
+}
+
+/// Sets the no-execute bit on the level 4 entries of the physical memory mapping, which covers
+/// every page below them.
+fn protect_physical_memory_mapping(physical_memory_size: u64) {
+    let offset = VirtAddr::new(super::PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+    let first = usize::from(offset.p4_index());
+    let last = usize::from((offset + (physical_memory_size - 1)).p4_index());
+    let image_index = usize::from(unsafe { VirtAddr::from_ptr(&__ehdr_start) }.p4_index());
+
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table = unsafe {
+        &mut *((offset + level_4_frame.start_address().as_u64()).as_mut_ptr::<PageTable>())
+    };
+    for index in first..=last {
+        // The bootloader can put the mapping right next to the kernel when memory is tight
+        if index == image_index {
+            continue;
+        }
+        let entry = &mut level_4_table[index];
+        if !entry.is_unused() {
+            entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+        }
+    }
+    tlb::flush_all();
+}
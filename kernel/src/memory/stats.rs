@@ -0,0 +1,184 @@
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+};
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::allocator::{self, HeapStats};
+
+const FRAME_SIZE: u64 = 4096;
+
+static STACK_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Subsystem attribution
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Users of physical memory that frames get attributed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Stacks,
+    Heap,
+    PageTables,
+    /// Firmware frames of the ACPI tables the kernel has mapped right now. They lie in reserved
+    /// regions, so unlike the others they are not part of the used frames.
+    Acpi,
+    /// Used frames that are not attributed to any other subsystem
+    Other,
+}
+
+/// Records that `frames` frames were mapped for thread stacks.
+pub fn stack_frames_mapped(frames: u64) {
+    STACK_FRAMES.fetch_add(frames, Ordering::Relaxed);
+}
+
+/// Records that `frames` frames of thread stacks were returned to the frame allocator.
+pub fn stack_frames_freed(frames: u64) {
+    STACK_FRAMES.fetch_sub(frames, Ordering::Relaxed);
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Statistics
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// A snapshot of the physical memory and heap usage. All frame counts are in 4 KiB frames.
+#[derive(Debug, Clone)]
+pub struct MemoryStats {
+    /// Frames covered by the memory map, whatever their type
+    pub total_frames: u64,
+    /// Frames the frame allocator hands out
+    pub usable_frames: u64,
+    /// Frames that are not usable, because they belong to firmware, the kernel image, etc.
+    pub reserved_frames: u64,
+    /// Usable frames that are currently allocated
+    pub used_frames: u64,
+    pub free_frames: u64,
+    /// Frame count of every region type in the memory map
+    pub regions: Vec<(MemoryRegionType, u64)>,
+    /// Frame count of every subsystem
+    pub subsystems: [(Subsystem, u64); 5],
+    pub heap: HeapStats,
+}
+
+impl MemoryStats {
+    /// Prints the statistics through the logger.
+    pub fn log(&self) {
+        info!(
+            "Memory: {} KiB total, {} KiB usable, {} KiB reserved, {} KiB used, {} KiB free",
+            self.total_frames * 4,
+            self.usable_frames * 4,
+            self.reserved_frames * 4,
+            self.used_frames * 4,
+            self.free_frames * 4,
+        );
+        for (region_type, frames) in &self.regions {
+            info!("  {:?}: {} KiB", region_type, frames * 4);
+        }
+        for (subsystem, frames) in &self.subsystems {
+            info!("  {:?}: {} KiB", subsystem, frames * 4);
+        }
+        info!(
+            "Heap: {} bytes mapped, {} allocated, {} overhead, {} free, {} fragmented ({}%), high water mark {}, limit {}",
+            self.heap.size,
+            self.heap.allocated,
+            self.heap.overhead,
+            self.heap.free,
+            self.heap.fragmented,
+            self.heap.fragmentation_percent(),
+            self.heap.high_water_mark,
+            self.heap.limit,
+        );
+    }
+}
+
+/// Collects the current memory statistics.
+///
+/// Panics if the frame allocator is not initialized yet.
+pub fn stats() -> MemoryStats {
+    let (memory_map, usable_frames, free_frames) = {
+        let frame_allocator = super::FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_ref().expect("frame allocator not initialized");
+        (
+            frame_allocator.memory_map(),
+            frame_allocator.usable_frames() as u64,
+            frame_allocator.free_frames() as u64,
+        )
+    };
+
+    let regions = frames_by_region_type(memory_map);
+    let total_frames = regions.iter().map(|(_, frames)| frames).sum::<u64>();
+    let used_frames = usable_frames - free_frames;
+
+    let heap = allocator::heap_stats();
+    let stacks = STACK_FRAMES.load(Ordering::Relaxed);
+    let heap_frames = heap.size as u64 / FRAME_SIZE;
+    let page_tables = active_page_table_frames();
+    let acpi = super::vma::pages_of_kind(super::vma::RegionKind::Acpi);
+    // The bootloader's page tables come from reserved regions, so this can't just subtract
+    let other = used_frames.saturating_sub(stacks + heap_frames + page_tables);
+
+    MemoryStats {
+        total_frames,
+        usable_frames,
+        reserved_frames: total_frames - usable_frames,
+        used_frames,
+        free_frames,
+        regions,
+        subsystems: [
+            (Subsystem::Stacks, stacks),
+            (Subsystem::Heap, heap_frames),
+            (Subsystem::PageTables, page_tables),
+            (Subsystem::Acpi, acpi),
+            (Subsystem::Other, other),
+        ],
+        heap,
+    }
+}
+
+/// Prints the current memory statistics through the logger.
+pub fn log_stats() {
+    stats().log();
+}
+
+/// Counts the usable frames in the memory map, without needing the frame allocator or the heap.
+pub fn usable_memory(memory_map: &MemoryMap) -> u64 {
+    memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+        .map(|region| (region.range.end_addr() - region.range.start_addr()) / FRAME_SIZE)
+        .sum::<u64>() * FRAME_SIZE
+}
+
+fn frames_by_region_type(memory_map: &MemoryMap) -> Vec<(MemoryRegionType, u64)> {
+    let mut regions: Vec<(MemoryRegionType, u64)> = Vec::new();
+    for region in memory_map.iter() {
+        let frames = (region.range.end_addr() - region.range.start_addr()) / FRAME_SIZE;
+        match regions.iter_mut().find(|(region_type, _)| *region_type == region.region_type) {
+            Some((_, count)) => *count += frames,
+            None => regions.push((region.region_type, frames)),
+        }
+    }
+    regions
+}
+
+/// Counts the page tables of the active address space, including the level 4 table.
+fn active_page_table_frames() -> u64 {
+    fn count(frame: PhysFrame, level: u8) -> u64 {
+        let physical_memory_offset = super::PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+        let table = unsafe { &*((physical_memory_offset + frame.start_address().as_u64()) as *const PageTable) };
+        let mut tables = 1;
+        if level > 1 {
+            for entry in table.iter() {
+                let flags = entry.flags();
+                if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE) {
+                    tables += count(PhysFrame::containing_address(entry.addr()), level - 1);
+                }
+            }
+        }
+        tables
+    }
+
+    count(Cr3::read().0, 4)
+}
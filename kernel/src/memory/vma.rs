@@ -0,0 +1,253 @@
+use x86_64::{
+    structures::paging::{Page, PageSize, Size4KiB},
+    VirtAddr,
+};
+
+use alloc::collections::BTreeMap;
+
+use crate::println;
+
+/// Start of the window dynamic kernel regions (stacks, MMIO, ACPI mappings) are placed in
+pub const VMA_WINDOW_START: u64 = 0x_5555_5555_0000;
+/// End of the window dynamic kernel regions are placed in
+pub const VMA_WINDOW_END: u64 = 0x_6000_0000_0000;
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Regions
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Heap,
+    Stack,
+    Mmio,
+    Acpi,
+}
+
+/// A range of kernel virtual address space that is in use. The guard pages lie directly
+/// below `start` and are never mapped, so running off the bottom of the region faults.
+///
+/// Pages of a lazy region are only backed by a frame when they are first touched, the page
+/// fault handler maps a zeroed frame in that case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualRegion {
+    start: VirtAddr,
+    pages: u64,
+    guard_pages: u64,
+    kind: RegionKind,
+    name: &'static str,
+    lazy: bool,
+}
+
+impl VirtualRegion {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.pages * PAGE_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        self.pages * PAGE_SIZE
+    }
+
+    pub fn pages(&self) -> u64 {
+        self.pages
+    }
+
+    pub fn guard_pages(&self) -> u64 {
+        self.guard_pages
+    }
+
+    pub fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
+    }
+
+    /// Returns the pages of the region, excluding the guard pages.
+    pub fn page_range(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let start = Page::containing_address(self.start);
+        Page::range(start, start + self.pages)
+    }
+
+    /// Returns true if `addr` lies in the region, excluding the guard pages.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    /// Returns true if `addr` lies in one of the guard pages of the region.
+    pub fn guard_contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.reserved_start() && addr < self.start
+    }
+
+    fn reserved_start(&self) -> VirtAddr {
+        self.start - self.guard_pages * PAGE_SIZE
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Region manager
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Keeps track of the kernel virtual address space that is in use, keyed by the first
+/// address of each region (including its guard pages).
+pub struct VirtualMemoryManager {
+    regions: BTreeMap<u64, VirtualRegion>,
+}
+
+impl VirtualMemoryManager {
+    pub fn new() -> Self {
+        Self {
+            regions: BTreeMap::new(),
+        }
+    }
+
+    /// Finds a free range in the dynamic window for `pages` pages preceded by
+    /// `guard_pages` guard pages, and marks it as used.
+    pub fn allocate(&mut self, pages: u64, guard_pages: u64, kind: RegionKind, name: &'static str) -> Option<VirtualRegion> {
+        self.allocate_region(pages, guard_pages, kind, name, false)
+    }
+
+    /// Like `allocate`, but the region is backed on demand by the page fault handler.
+    pub fn allocate_lazy(&mut self, pages: u64, guard_pages: u64, kind: RegionKind, name: &'static str) -> Option<VirtualRegion> {
+        self.allocate_region(pages, guard_pages, kind, name, true)
+    }
+
+    fn allocate_region(&mut self, pages: u64, guard_pages: u64, kind: RegionKind, name: &'static str, lazy: bool) -> Option<VirtualRegion> {
+        let needed = (pages + guard_pages) * PAGE_SIZE;
+
+        // First fit search through the gaps between the regions
+        let mut cursor = VMA_WINDOW_START;
+        for region in self.regions.values() {
+            let region_start = region.reserved_start().as_u64();
+            let region_end = region.end().as_u64();
+            if region_end <= cursor {
+                continue;
+            }
+            if region_start >= cursor && region_start - cursor >= needed {
+                break;
+            }
+            cursor = region_end;
+        }
+        if cursor + needed > VMA_WINDOW_END {
+            return None;
+        }
+
+        let region = VirtualRegion {
+            start: VirtAddr::new(cursor + guard_pages * PAGE_SIZE),
+            pages,
+            guard_pages,
+            kind,
+            name,
+            lazy,
+        };
+        self.regions.insert(cursor, region);
+        Some(region)
+    }
+
+    /// Marks a fixed range as used, for regions whose location is decided elsewhere.
+    /// Returns `None` if the range overlaps with a region that is already in use.
+    pub fn reserve(&mut self, start: VirtAddr, pages: u64, kind: RegionKind, name: &'static str) -> Option<VirtualRegion> {
+        assert!(start.is_aligned(PAGE_SIZE), "region start {:?} is not page aligned", start);
+        let region = VirtualRegion {
+            start,
+            pages,
+            guard_pages: 0,
+            kind,
+            name,
+            lazy: false,
+        };
+        let overlaps = self.regions.values().any(|other| {
+            other.reserved_start() < region.end() && region.start < other.end()
+        });
+        if overlaps {
+            return None;
+        }
+        self.regions.insert(start.as_u64(), region);
+        Some(region)
+    }
+
+    /// Releases the region starting at `start`, making its range available again.
+    /// The pages of the region must already be unmapped by the caller.
+    pub fn free(&mut self, start: VirtAddr) -> Option<VirtualRegion> {
+        let key = self.regions
+            .iter()
+            .find(|(_, region)| region.start == start)
+            .map(|(&key, _)| key)?;
+        self.regions.remove(&key)
+    }
+
+    /// Finds the region that contains `addr`, including its guard pages.
+    pub fn find(&self, addr: VirtAddr) -> Option<VirtualRegion> {
+        self.regions
+            .range(..=addr.as_u64())
+            .next_back()
+            .map(|(_, region)| *region)
+            .filter(|region| region.contains(addr) || region.guard_contains(addr))
+    }
+
+    /// Amount of pages in the regions of `kind`, not counting guard pages.
+    pub fn pages_of_kind(&self, kind: RegionKind) -> u64 {
+        self.regions.values().filter(|region| region.kind == kind).map(|region| region.pages).sum()
+    }
+
+    pub fn debug_print(&self) {
+        println!("=====VMA=====");
+        for region in self.regions.values() {
+            println!(
+                "{:#014x}-{:#014x} {:>8} KiB {:?} \"{}\" (guard pages: {}{})",
+                region.start.as_u64(),
+                region.end().as_u64(),
+                region.size() / 1024,
+                region.kind,
+                region.name,
+                region.guard_pages,
+                if region.lazy { ", lazy" } else { "" },
+            );
+        }
+        println!("=====++++=====");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Singleton region manager
+///////////////////////////////////////////////////////////////////////////////////////////////////
+lazy_static! {
+    pub static ref KERNEL_VMA: spin::Mutex<VirtualMemoryManager> = spin::Mutex::new(VirtualMemoryManager::new());
+}
+
+pub fn allocate(pages: u64, guard_pages: u64, kind: RegionKind, name: &'static str) -> Option<VirtualRegion> {
+    KERNEL_VMA.lock().allocate(pages, guard_pages, kind, name)
+}
+
+pub fn allocate_lazy(pages: u64, guard_pages: u64, kind: RegionKind, name: &'static str) -> Option<VirtualRegion> {
+    KERNEL_VMA.lock().allocate_lazy(pages, guard_pages, kind, name)
+}
+
+pub fn reserve(start: VirtAddr, pages: u64, kind: RegionKind, name: &'static str) -> Option<VirtualRegion> {
+    KERNEL_VMA.lock().reserve(start, pages, kind, name)
+}
+
+pub fn free(start: VirtAddr) -> Option<VirtualRegion> {
+    KERNEL_VMA.lock().free(start)
+}
+
+pub fn find(addr: VirtAddr) -> Option<VirtualRegion> {
+    KERNEL_VMA.lock().find(addr)
+}
+
+pub fn pages_of_kind(kind: RegionKind) -> u64 {
+    KERNEL_VMA.lock().pages_of_kind(kind)
+}
+
+pub fn debug_print() {
+    KERNEL_VMA.lock().debug_print();
+}
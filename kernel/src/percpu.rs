@@ -0,0 +1,239 @@
+//! Per-CPU data, reachable through the GS base of each CPU.
+//!
+//! While the CPU runs kernel code, `GS.base` points at the `PerCpu` block of that CPU and
+//! `KernelGSBase` holds the user value. Interrupts and system calls coming from user mode have
+//! to `swapgs` on entry and exit to keep it that way, see `InterruptGs`.
+//!
+//! Only the bootstrap CPU is brought up for now, its block is a static.
+
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::{idt::InterruptStackFrame, tss::TaskStateSegment},
+    VirtAddr,
+};
+
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::interrupts::stats::InterruptStats;
+use crate::interrupts::watchdog::WatchdogState;
+use crate::threading::thread::ThreadId;
+
+const GS_BASE_MSR: u32 = 0xC000_0101;
+const KERNEL_GS_BASE_MSR: u32 = 0xC000_0102;
+
+/// Number of 64 bit scratch slots in every per-CPU block
+pub const SCRATCH_SLOTS: usize = 4;
+/// Offset of the scratch slots from the GS base, for assembly code like `mov gs:[8], rsp`
+pub const SCRATCH_OFFSET: usize = 8;
+
+static mut BSP: PerCpu = PerCpu::new();
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Per-CPU block
+///////////////////////////////////////////////////////////////////////////////////////////////////
+#[repr(C)]
+pub struct PerCpu {
+    /// Points at the block itself, so `this_cpu` needs a single GS relative load
+    self_ptr: *const PerCpu,
+    /// Free for assembly code, e.g. to stash a register before the stack can be used
+    scratch: UnsafeCell<[u64; SCRATCH_SLOTS]>,
+    index: usize,
+    apic_id: u8,
+    /// Id of the running thread, 0 before the scheduler is created
+    current_thread: AtomicU64,
+    run_queue: RunQueue,
+    tss: TaskStateSegment,
+    interrupt_stats: InterruptStats,
+    watchdog: WatchdogState,
+}
+
+// Only the owning CPU touches the scratch slots, everything else is immutable after init or
+// synchronized
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    const fn new() -> Self {
+        Self {
+            self_ptr: core::ptr::null(),
+            scratch: UnsafeCell::new([0; SCRATCH_SLOTS]),
+            index: 0,
+            apic_id: 0,
+            current_thread: AtomicU64::new(0),
+            run_queue: RunQueue::new(),
+            tss: TaskStateSegment::new(),
+            interrupt_stats: InterruptStats::new(),
+            watchdog: WatchdogState::new(),
+        }
+    }
+
+    /// Index of the CPU, 0 for the bootstrap CPU.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id
+    }
+
+    pub fn current_thread_id(&self) -> Option<u64> {
+        match self.current_thread.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    pub(crate) fn set_current_thread_id(&self, id: ThreadId) {
+        self.current_thread.store(id.as_u64(), Ordering::Relaxed);
+    }
+
+    /// Threads that are ready to run on this CPU.
+    pub fn run_queue(&self) -> &RunQueue {
+        &self.run_queue
+    }
+
+    pub fn tss(&self) -> &TaskStateSegment {
+        &self.tss
+    }
+
+    pub fn scratch(&self) -> *mut [u64; SCRATCH_SLOTS] {
+        self.scratch.get()
+    }
+
+    /// Interrupts dispatched on this CPU.
+    pub fn interrupt_stats(&self) -> &InterruptStats {
+        &self.interrupt_stats
+    }
+
+    /// Hard-lockup watchdog state of this CPU.
+    pub fn watchdog(&self) -> &WatchdogState {
+        &self.watchdog
+    }
+}
+
+/// Sets up the per-CPU block of the bootstrap CPU and points the GS base at it. The TSS can't
+/// change after this, because the GDT refers to it.
+///
+/// This function is unsafe because it must be called once, before anything uses `this_cpu`.
+pub unsafe fn init_bsp(tss: TaskStateSegment) {
+    let cpu = &mut BSP;
+    cpu.self_ptr = cpu as *const PerCpu;
+    cpu.index = 0;
+    cpu.apic_id = initial_apic_id();
+    cpu.tss = tss;
+
+    Msr::new(GS_BASE_MSR).write(cpu.self_ptr as u64);
+    Msr::new(KERNEL_GS_BASE_MSR).write(0);
+    INITIALIZED.store(true, Ordering::SeqCst);
+}
+
+/// Returns the per-CPU block of the running CPU.
+pub fn this_cpu() -> &'static PerCpu {
+    debug_assert!(INITIALIZED.load(Ordering::Relaxed), "per-CPU data used before init");
+    let cpu: *const PerCpu;
+    unsafe { llvm_asm!("mov $0, gs:[0]" : "=r"(cpu) ::: "intel"); }
+    unsafe { &*cpu }
+}
+
+/// Iterates over the per-CPU blocks of all CPUs that are up.
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    try_this_cpu().map(|_| unsafe { &BSP }).into_iter()
+}
+
+/// Like `this_cpu`, but returns `None` instead of faulting before the GS base is set up.
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    if INITIALIZED.load(Ordering::Relaxed) {
+        Some(this_cpu())
+    } else {
+        None
+    }
+}
+
+/// The APIC ID the CPU started with, which doesn't need the local APIC to be mapped.
+fn initial_apic_id() -> u8 {
+    use core::arch::x86_64::__cpuid;
+    (unsafe { __cpuid(1) }.ebx >> 24) as u8
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Run queue
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// FIFO of runnable threads. The queue is created on first use, because creating an empty
+/// `VecDeque` allocates and the per-CPU block exists before the heap does.
+pub struct RunQueue {
+    threads: spin::Mutex<Option<VecDeque<ThreadId>>>,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self { threads: spin::Mutex::new(None) }
+    }
+
+    pub fn push_back(&self, thread_id: ThreadId) {
+        self.threads.lock().get_or_insert_with(VecDeque::new).push_back(thread_id);
+    }
+
+    pub fn pop_front(&self) -> Option<ThreadId> {
+        self.threads.lock().as_mut().and_then(|threads| threads.pop_front())
+    }
+
+    pub fn len(&self) -> usize {
+        self.threads.lock().as_ref().map_or(0, |threads| threads.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// swapgs
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Swaps the kernel GS base in for an interrupt that came from user mode, and back out again
+/// when dropped. Interrupts from kernel mode already have the kernel GS base, so nothing
+/// happens for them. Create it before calling `this_cpu` in an interrupt handler.
+pub struct InterruptGs {
+    from_user: bool,
+}
+
+impl InterruptGs {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let from_user = stack_frame.code_segment & 0b11 != 0;
+        if from_user {
+            unsafe { swapgs(); }
+        }
+        Self { from_user }
+    }
+}
+
+impl Drop for InterruptGs {
+    fn drop(&mut self) {
+        if self.from_user {
+            unsafe { swapgs(); }
+        }
+    }
+}
+
+unsafe fn swapgs() {
+    llvm_asm!("swapgs" :::: "intel", "volatile");
+}
+
+/// Returns the GS base, for diagnostics.
+pub fn gs_base() -> VirtAddr {
+    VirtAddr::new(unsafe { Msr::new(GS_BASE_MSR).read() })
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// The GS base points at the block of the running CPU, and the scratch slots are where
+/// assembly code expects them
+#[test_case]
+fn test_this_cpu() {
+    let cpu = this_cpu();
+    assert_eq!(gs_base(), VirtAddr::from_ptr(cpu));
+    assert_eq!(cpu.index(), 0);
+    assert_eq!(cpu.scratch() as usize - cpu as *const PerCpu as usize, SCRATCH_OFFSET);
+}
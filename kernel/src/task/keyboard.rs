@@ -10,21 +10,26 @@ use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 
 use crate::{print, println};
+use crate::interrupts::deferred::{self, Work};
 
 static WAKER: AtomicWaker = AtomicWaker::new();
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 
 /// Called by the keyboard interrupt handler
-/// Must not block or allocate.
+/// Must not block or allocate, the warnings are printed as deferred work.
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
+            let _ = deferred::schedule(Work::new("scancode warning", |_| {
+                println!("WARNING: scancode queue full; dropping keyboard input");
+            }, 0));
         } else {
             WAKER.wake();
         }
     } else {
-        println!("WARNING: scancode queue uninitialized");
+        let _ = deferred::schedule(Work::new("scancode warning", |_| {
+            println!("WARNING: scancode queue uninitialized");
+        }, 0));
     }
 }
 
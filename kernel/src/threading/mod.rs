@@ -3,6 +3,9 @@ pub mod scheduler;
 pub mod thread;
 
 use scheduler::Scheduler;
+use thread::ThreadId;
+
+use crate::percpu;
 
 static SCHEDULER: spin::Mutex<Option<Scheduler>> = spin::Mutex::new(None);
 
@@ -34,6 +37,33 @@ pub fn exit_thread() -> ! {
     unreachable!("finished thread continued");
 }
 
+/// Kills the running thread from an exception handler, the thread never runs again and its
+/// resources are reclaimed like those of an exited thread. Panics if the thread can't be killed,
+/// because the faulting code was holding the scheduler lock or there is nothing else to run.
+///
+/// The scheduler is never created here, that would allocate in an exception handler and the
+/// fault may have come from the allocator. Without a scheduler there is no thread to kill.
+pub fn kill_current_thread() -> ! {
+    let next = {
+        let mut scheduler = SCHEDULER
+            .try_lock()
+            .expect("faulted while holding the scheduler lock, can't kill the thread");
+        let scheduler = scheduler.as_mut().expect("faulted before the scheduler existed, no thread to kill");
+        if Some(scheduler.current_thread_id()) == scheduler.idle_thread_id() {
+            panic!("the idle thread faulted");
+        }
+        crate::println!("Killing thread {}", scheduler.current_thread_id().as_u64());
+        scheduler.schedule()
+    };
+    match next {
+        Some((next_stack_pointer, prev_thread_id)) => unsafe {
+            context_switch::context_switch_to(next_stack_pointer, prev_thread_id, SwitchReason::Exit);
+        },
+        None => panic!("can't kill the last thread"),
+    }
+    unreachable!("killed thread continued");
+}
+
 pub fn yield_now() {
     let _ = synchronous_context_switch(SwitchReason::Yield);
 }
@@ -49,6 +79,18 @@ fn synchronous_context_switch(reason: SwitchReason) -> Result<(), ()> {
     }
 }
 
+/// Returns the id of the running thread without taking the scheduler lock, for code that might
+/// run while the scheduler is locked, like the allocator. Returns `None` before the scheduler
+/// is created.
+pub fn current_thread_id_unlocked() -> Option<u64> {
+    percpu::try_this_cpu().and_then(|cpu| cpu.current_thread_id())
+}
+
+/// Mirrors the id of the running thread into the per-CPU block.
+fn set_current_thread_id(id: ThreadId) {
+    percpu::this_cpu().set_current_thread_id(id);
+}
+
 pub fn with_scheduler<F, T>(f: F) -> T
 where
     F: FnOnce(&mut Scheduler) -> T,
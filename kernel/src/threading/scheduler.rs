@@ -1,6 +1,10 @@
 use super::SwitchReason;
+use crate::memory::{self, address_space};
+use crate::percpu;
 use crate::threading::thread::{Thread, ThreadId};
-use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::mem;
 use x86_64::VirtAddr;
 
@@ -8,15 +12,16 @@ pub struct Scheduler {
     threads: BTreeMap<ThreadId, Thread>,
     idle_thread_id: Option<ThreadId>,
     current_thread_id: ThreadId,
-    paused_threads: VecDeque<ThreadId>,
     blocked_threads: BTreeSet<ThreadId>,
     wakeups: BTreeSet<ThreadId>,
+    exited_threads: Vec<Thread>,
 }
 
 impl Scheduler {
     pub fn new() -> Self {
         let root_thread = Thread::create_root_thread();
         let root_id = root_thread.id();
+        super::set_current_thread_id(root_id);
         let mut threads = BTreeMap::new();
         threads
             .insert(root_id, root_thread)
@@ -24,15 +29,16 @@ impl Scheduler {
         Scheduler {
             threads,
             current_thread_id: root_id,
-            paused_threads: VecDeque::new(),
             blocked_threads: BTreeSet::new(),
             wakeups: BTreeSet::new(),
             idle_thread_id: None,
+            exited_threads: Vec::new(),
         }
     }
 
+    /// Paused threads wait in the run queue of the CPU they ran on.
     fn next_thread(&mut self) -> Option<ThreadId> {
-        self.paused_threads.pop_front()
+        percpu::this_cpu().run_queue().pop_front()
     }
 
     pub fn schedule(&mut self) -> Option<(VirtAddr, ThreadId)> {
@@ -49,7 +55,14 @@ impl Scheduler {
                 .stack_pointer()
                 .take()
                 .expect("paused thread has no stack pointer");
+            // Kernel stacks live in the shared kernel half, so switching page tables before
+            // switching stacks is fine
+            match next_thread.address_space() {
+                Some(address_space) => unsafe { address_space.activate() },
+                None => address_space::activate_kernel_address_space(),
+            }
             let prev_thread_id = mem::replace(&mut self.current_thread_id, next_thread.id());
+            super::set_current_thread_id(next_thread.id());
             Some((next_stack_pointer, prev_thread_id))
         } else {
             None
@@ -75,7 +88,7 @@ impl Scheduler {
         }
         match switch_reason {
             SwitchReason::Paused | SwitchReason::Yield => {
-                self.paused_threads.push_back(paused_thread_id)
+                percpu::this_cpu().run_queue().push_back(paused_thread_id)
             }
             SwitchReason::Blocked => {
                 self.blocked_threads.insert(paused_thread_id);
@@ -86,9 +99,44 @@ impl Scheduler {
                     .threads
                     .remove(&paused_thread_id)
                     .expect("thread not found");
-                // TODO: free stack memory again
+                self.exited_threads.push(thread);
             }
         }
+        self.reap_exited_threads();
+    }
+
+    /// Frees the stacks and address spaces of exited threads. This runs after the switch away
+    /// from the exited thread, so neither is in use anymore. If the mapper or frame allocator
+    /// is locked by someone else, the threads are kept around and reaped on a later switch.
+    fn reap_exited_threads(&mut self) {
+        if self.exited_threads.is_empty() {
+            return;
+        }
+        {
+            let mut mapper = match memory::MAPPER.try_lock() {
+                Some(mapper) => mapper,
+                None => return,
+            };
+            let mut frame_allocator = match memory::FRAME_ALLOCATOR.try_lock() {
+                Some(frame_allocator) => frame_allocator,
+                None => return,
+            };
+            if let (Some(mapper), Some(frame_allocator)) = (mapper.as_mut(), frame_allocator.as_mut()) {
+                for thread in self.exited_threads.iter_mut() {
+                    if let Some(stack_bounds) = thread.stack_bounds() {
+                        unsafe { memory::free_stack(stack_bounds, mapper, frame_allocator) }
+                            .expect("failed to free the stack of an exited thread");
+                    }
+                    // Dropping the last reference would lock the frame allocator, which is held here
+                    if let Some(Ok(address_space)) = thread.take_address_space().map(Arc::try_unwrap) {
+                        address_space.free(frame_allocator);
+                    }
+                }
+            } else {
+                return;
+            }
+        }
+        self.exited_threads.clear();
     }
 
     pub fn add_new_thread(&mut self, thread: Thread) {
@@ -96,7 +144,7 @@ impl Scheduler {
         self.threads
             .insert(thread_id, thread)
             .expect_none("thread already exists");
-        self.paused_threads.push_back(thread_id);
+        percpu::this_cpu().run_queue().push_back(thread_id);
     }
 
     pub fn set_idle_thread(&mut self, thread: Thread) {
@@ -113,10 +161,14 @@ impl Scheduler {
         self.current_thread_id
     }
 
+    pub fn idle_thread_id(&self) -> Option<ThreadId> {
+        self.idle_thread_id
+    }
+
     fn check_for_wakeup(&mut self, thread_id: ThreadId) {
         if self.wakeups.remove(&thread_id) {
             assert!(self.blocked_threads.remove(&thread_id));
-            self.paused_threads.push_back(thread_id);
+            percpu::this_cpu().run_queue().push_back(thread_id);
         }
     }
 }
@@ -1,8 +1,10 @@
-use crate::memory::{alloc_stack, StackBounds};
+use crate::memory::{alloc_stack, StackBounds, StackError};
+use crate::memory::address_space::AddressSpace;
 use crate::threading::context_switch::Stack;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use x86_64::{
-    structures::paging::{mapper, FrameAllocator, Mapper, Size4KiB},
+    structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Size4KiB},
     VirtAddr,
 };
 
@@ -27,6 +29,7 @@ pub struct Thread {
     id: ThreadId,
     stack_pointer: Option<VirtAddr>,
     stack_bounds: Option<StackBounds>,
+    address_space: Option<Arc<AddressSpace>>,
 }
 
 impl Thread {
@@ -34,8 +37,8 @@ impl Thread {
         entry_point: fn() -> !,
         stack_size: u64,
         mapper: &mut impl Mapper<Size4KiB>,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-    ) -> Result<Self, mapper::MapToError<Size4KiB>> {
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<Self, StackError> {
         let stack_bounds = alloc_stack(stack_size, mapper, frame_allocator)?;
         let mut stack = unsafe { Stack::new(stack_bounds.end()) };
         stack.set_up_for_entry_point(entry_point);
@@ -46,8 +49,8 @@ impl Thread {
         closure: F,
         stack_size: u64,
         mapper: &mut impl Mapper<Size4KiB>,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-    ) -> Result<Self, mapper::MapToError<Size4KiB>>
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<Self, StackError>
     where
         F: FnOnce() -> ! + 'static + Send + Sync,
     {
@@ -62,6 +65,7 @@ impl Thread {
             id: ThreadId::new(),
             stack_pointer: Some(stack_pointer),
             stack_bounds: Some(stack_bounds),
+            address_space: None,
         }
     }
 
@@ -70,6 +74,7 @@ impl Thread {
             id: ThreadId(0),
             stack_pointer: None,
             stack_bounds: None,
+            address_space: None,
         }
     }
 
@@ -80,4 +85,25 @@ impl Thread {
     pub(super) fn stack_pointer(&mut self) -> &mut Option<VirtAddr> {
         &mut self.stack_pointer
     }
+
+    pub(super) fn stack_bounds(&self) -> Option<StackBounds> {
+        self.stack_bounds
+    }
+
+    /// The address space the thread runs in, `None` for kernel threads that only use the
+    /// kernel page table.
+    pub fn address_space(&self) -> Option<&Arc<AddressSpace>> {
+        self.address_space.as_ref()
+    }
+
+    /// Makes the thread run in `address_space`. Threads sharing an address space keep it alive,
+    /// it is torn down when the last of them exits.
+    pub fn set_address_space(&mut self, address_space: Arc<AddressSpace>) {
+        self.address_space = Some(address_space);
+    }
+
+    /// Takes the address space out of the thread, so it can be freed explicitly.
+    pub(super) fn take_address_space(&mut self) -> Option<Arc<AddressSpace>> {
+        self.address_space.take()
+    }
 }
@@ -0,0 +1,173 @@
+#![no_std]
+#![no_main]
+
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+
+use alloc::sync::Arc;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{MapperAllSizes, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use kernel::memory::address_space::{self, AddressSpace};
+use kernel::memory::cow;
+use kernel::memory::{BitmapFrameAllocator, FRAME_ALLOCATOR, MAPPER};
+use kernel::threading::{self, thread::Thread, with_scheduler};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init();
+    kernel::memory::update_physical_memory_offset(boot_info.physical_memory_offset);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        *mapper = unsafe { Some(kernel::memory::init(phys_mem_offset)) };
+        *frame_allocator = unsafe {
+            Some(BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset))
+        };
+        kernel::allocator::init_heap(mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+            .expect("heap initialization failed");
+        address_space::init_kernel_half(frame_allocator.as_mut().unwrap());
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+fn new_address_space() -> AddressSpace {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    AddressSpace::new(frame_allocator.as_mut().unwrap()).expect("out of frames")
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// CR3 carries the PCID of the active address space, and the kernel page table uses PCID 0
+#[test_case]
+fn activate_loads_pcid() {
+    let kernel_frame = Cr3::read().0;
+    let space = new_address_space();
+    unsafe { space.activate(); }
+    assert!(space.is_active());
+    let expected = if address_space::pcid_enabled() { space.pcid() } else { 0 };
+    assert_eq!(address_space::active_pcid(), expected);
+
+    address_space::activate_kernel_address_space();
+    assert_eq!(Cr3::read().0, kernel_frame);
+    assert_eq!(address_space::active_pcid(), 0);
+    // Loading it again keeps its TLB entries, which must not break anything
+    unsafe { space.activate(); }
+    assert!(space.is_active());
+    address_space::activate_kernel_address_space();
+
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    space.free(frame_allocator.as_mut().unwrap());
+}
+
+static THREAD_CR3: AtomicU64 = AtomicU64::new(0);
+static THREAD_PCID: AtomicU64 = AtomicU64::new(0);
+static THREAD_DONE: AtomicBool = AtomicBool::new(false);
+
+fn record_address_space() -> ! {
+    THREAD_CR3.store(Cr3::read().0.start_address().as_u64(), Ordering::SeqCst);
+    THREAD_PCID.store(address_space::active_pcid().into(), Ordering::SeqCst);
+    THREAD_DONE.store(true, Ordering::SeqCst);
+    threading::exit_thread();
+}
+
+/// A thread runs in the address space it was given, and the scheduler frees the address space
+/// once its last thread exited
+#[test_case]
+fn thread_runs_in_address_space() {
+    let kernel_frame = Cr3::read().0;
+    let space = Arc::new(new_address_space());
+    let level_4_frame = space.level_4_frame();
+    let pcid = space.pcid();
+    let weak = Arc::downgrade(&space);
+    {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let mut thread = Thread::create(record_address_space, 2, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+            .expect("failed to create thread");
+        thread.set_address_space(space);
+        with_scheduler(|s| s.add_new_thread(thread));
+    }
+
+    // The mapper and frame allocator are unlocked, so the exited thread is reaped on the way back
+    for _ in 0..10 {
+        if THREAD_DONE.load(Ordering::SeqCst) {
+            break;
+        }
+        threading::yield_now();
+    }
+    assert!(THREAD_DONE.load(Ordering::SeqCst), "thread never ran");
+    assert_eq!(THREAD_CR3.load(Ordering::SeqCst), level_4_frame.start_address().as_u64());
+    if address_space::pcid_enabled() {
+        assert_eq!(THREAD_PCID.load(Ordering::SeqCst), u64::from(pcid));
+    }
+    assert_eq!(Cr3::read().0, kernel_frame);
+    assert!(weak.upgrade().is_none(), "address space of the exited thread was not freed");
+}
+
+/// A write to a page shared by `clone_cow` gives the writer a private copy, and leaves the other
+/// address space as the only owner of the original frame
+#[test_case]
+fn clone_cow_copies_on_write() {
+    let addr = VirtAddr::new(0x1000_0000);
+    let mut parent = new_address_space();
+    let parent_frame = {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        parent.map_user_page(Page::containing_address(addr), PageTableFlags::WRITABLE, frame_allocator.as_mut().unwrap())
+            .expect("failed to map user page")
+    };
+    unsafe {
+        parent.activate();
+        addr.as_mut_ptr::<u64>().write_volatile(1);
+    }
+    address_space::activate_kernel_address_space();
+
+    let mut child = {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        parent.clone_cow(frame_allocator.as_mut().unwrap()).expect("out of frames")
+    };
+    assert_eq!(cow::share_count(parent_frame), 2);
+
+    // The frame allocator is unlocked, so the fault handler can allocate the copy
+    unsafe {
+        child.activate();
+        assert_eq!(addr.as_ptr::<u64>().read_volatile(), 1);
+        addr.as_mut_ptr::<u64>().write_volatile(2);
+    }
+    address_space::activate_kernel_address_space();
+
+    let child_phys = child.mapper().translate_addr(addr).expect("child page not mapped");
+    assert_ne!(child_phys, parent_frame.start_address());
+    assert_eq!(cow::share_count(parent_frame), 1);
+    unsafe {
+        parent.activate();
+        assert_eq!(addr.as_ptr::<u64>().read_volatile(), 1);
+        // The last mapping owns the frame again, writing just makes it writable
+        addr.as_mut_ptr::<u64>().write_volatile(3);
+    }
+    address_space::activate_kernel_address_space();
+    assert_eq!(parent.mapper().translate_addr(addr), Some(parent_frame.start_address()));
+
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    child.free(frame_allocator.as_mut().unwrap());
+    parent.free(frame_allocator.as_mut().unwrap());
+}
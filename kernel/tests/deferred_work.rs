@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use kernel::interrupts::deferred::{self, ScheduleError, Work, QUEUE_CAPACITY};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        *mapper = unsafe { Some(memory::init(phys_mem_offset)) };
+        *frame_allocator = unsafe {
+            Some(BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset))
+        };
+        allocator::init_heap(mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+            .expect("heap initialization failed");
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Work scheduled before the queue exists is dropped, and so is work that doesn't fit
+#[test_case]
+fn schedule_until_full() {
+    let work = Work::new("test", |_| {}, 0);
+    assert_eq!(deferred::schedule(work), Err(ScheduleError::Uninitialized));
+    assert_eq!(deferred::dropped_count(), 1);
+
+    deferred::init();
+    for _ in 0..QUEUE_CAPACITY {
+        deferred::schedule(work).expect("queue full too early");
+    }
+    assert_eq!(deferred::pending_count(), QUEUE_CAPACITY);
+    assert_eq!(deferred::schedule(work), Err(ScheduleError::QueueFull));
+    assert_eq!(deferred::dropped_count(), 2);
+}
+
+static RAN: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+static RAN_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn record(data: u64) {
+    RAN[RAN_COUNT.fetch_add(1, Ordering::SeqCst)].store(data, Ordering::SeqCst);
+}
+
+/// Pending work runs in the order it was scheduled and leaves the queue empty. Running work
+/// needs interrupts enabled, so the PICs are remapped and masked first, nothing else can raise
+/// an interrupt without the APIC set up.
+#[test_case]
+fn run_pending_in_order() {
+    unsafe { kernel::apic::disable_pic(); }
+    x86_64::instructions::interrupts::enable();
+
+    // Whatever the test before left behind
+    deferred::run_pending();
+    assert_eq!(deferred::pending_count(), 0);
+
+    for data in 1..=3 {
+        deferred::schedule(Work::new("record", record, data)).expect("queue full");
+    }
+    assert_eq!(deferred::pending_count(), 3);
+    assert_eq!(deferred::run_pending(), 3);
+    assert_eq!(deferred::pending_count(), 0);
+    assert_eq!(RAN_COUNT.load(Ordering::SeqCst), 3);
+    for (index, ran) in RAN.iter().enumerate() {
+        assert_eq!(ran.load(Ordering::SeqCst), index as u64 + 1);
+    }
+
+    x86_64::instructions::interrupts::disable();
+}
@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+
+use core::panic::PanicInfo;
+
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageTable, PageTableFlags};
+use x86_64::VirtAddr;
+
+use kernel::memory::{mapping, BitmapFrameAllocator, DmaBuffer, DmaZone, FRAME_ALLOCATOR, MAPPER};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init();
+    kernel::memory::update_physical_memory_offset(boot_info.physical_memory_offset);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    *MAPPER.lock() = unsafe { Some(kernel::memory::init(phys_mem_offset)) };
+    *FRAME_ALLOCATOR.lock() = unsafe {
+        Some(BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset))
+    };
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Freed frames can be handed out again
+#[test_case]
+fn reuse_freed_frame() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    let free_before = allocator.free_frames();
+    let frame = allocator.allocate_frame().expect("out of frames");
+    assert_eq!(allocator.free_frames(), free_before - 1);
+
+    unsafe { allocator.deallocate_frame(frame); }
+    assert_eq!(allocator.free_frames(), free_before);
+    assert_eq!(allocator.allocate_frame(), Some(frame));
+    unsafe { allocator.deallocate_frame(frame); }
+}
+
+/// Contiguous allocations are contiguous and aligned
+#[test_case]
+fn contiguous_allocation() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    let start = allocator.allocate_contiguous(16, 8).expect("out of contiguous frames");
+    assert_eq!(start.start_address().as_u64() % (8 * 4096), 0);
+
+    let frame = allocator.allocate_frame().expect("out of frames");
+    assert!(frame < start || frame >= start + 16);
+
+    unsafe {
+        allocator.deallocate_frame(frame);
+        allocator.deallocate_contiguous(start, 16);
+    }
+}
+
+/// DMA buffers stay inside their zone, are zeroed and give their frames back when dropped
+#[test_case]
+fn dma_buffer() {
+    let free_before = FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames();
+
+    let mut buffer = DmaBuffer::allocate(3 * 4096 + 1, 64 * 1024, DmaZone::Isa).expect("out of DMA memory");
+    let phys = buffer.phys_addr().as_u64();
+    assert_eq!(phys % (64 * 1024), 0);
+    assert!(phys + 4 * 4096 <= DmaZone::Isa.limit().as_u64());
+    assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+    buffer.as_mut_slice()[0] = 0xAB;
+    assert_eq!(unsafe { *buffer.virt_addr().as_ptr::<u8>() }, 0xAB);
+    assert_eq!(FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames(), free_before - 4);
+
+    drop(buffer);
+    assert_eq!(FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames(), free_before);
+}
+
+/// Addresses inside a 2 MiB page translate to the right frame, also when the PAT bit (bit 12
+/// of a huge page entry) is set
+#[test_case]
+fn translate_huge_page() {
+    const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+    const FRAMES: usize = (HUGE_PAGE_SIZE / 4096) as usize;
+    let virt = VirtAddr::new(0x_7000_0000_0000);
+    let phys_mem_offset = VirtAddr::new(kernel::memory::PHYSICAL_MEMORY_OFFSET.load(core::sync::atomic::Ordering::Relaxed));
+
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = (mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap());
+    let start = frame_allocator.allocate_contiguous(FRAMES, FRAMES).expect("out of contiguous frames");
+    let phys = start.start_address();
+    unsafe {
+        mapping::map_range(virt, phys, HUGE_PAGE_SIZE, PageTableFlags::PRESENT, mapper, frame_allocator)
+            .expect("failed to map huge page");
+    }
+
+    let addr = virt + 0x1_2345u64;
+    let translate = || unsafe { kernel::memory::translate_addr(addr, phys_mem_offset) };
+    assert_eq!(translate(), Some(phys + 0x1_2345u64));
+
+    // Set the PAT bit of the level 2 entry by hand, the page table flags have no name for it
+    let entry = unsafe {
+        let mut table = x86_64::registers::control::Cr3::read().0.start_address();
+        for &index in &[virt.p4_index(), virt.p3_index()] {
+            let entries = &*(phys_mem_offset + table.as_u64()).as_ptr::<PageTable>();
+            table = entries[index].addr();
+        }
+        let entries = &mut *(phys_mem_offset + table.as_u64()).as_mut_ptr::<PageTable>();
+        &mut entries[virt.p2_index()] as *mut _ as *mut u64
+    };
+    unsafe { *entry |= 1 << 12; }
+    x86_64::instructions::tlb::flush(virt);
+    assert_eq!(translate(), Some(phys + 0x1_2345u64));
+    // The mapper doesn't know about the PAT bit either
+    unsafe { *entry &= !(1 << 12); }
+    x86_64::instructions::tlb::flush(virt);
+
+    mapping::unmap_range(virt, HUGE_PAGE_SIZE, mapper, |_, _| {}).expect("failed to unmap huge page");
+    unsafe { frame_allocator.deallocate_contiguous(start, FRAMES); }
+}
@@ -18,17 +18,21 @@ entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use kernel::allocator;
-    use kernel::memory::{self, BootInfoFrameAllocator};
+    use kernel::memory::{self, BitmapFrameAllocator};
     use x86_64::VirtAddr;
 
     kernel::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("heap initialization failed");
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        *mapper = unsafe { Some(memory::init(phys_mem_offset)) };
+        *frame_allocator = unsafe {
+            Some(BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset))
+        };
+        allocator::init_heap(mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+            .expect("heap initialization failed");
+    }
 
     test_main();
     loop {}
@@ -70,3 +74,82 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
 }
+
+/// Allocations bigger than the initial heap make it grow
+#[test_case]
+fn heap_growth() {
+    use kernel::allocator;
+
+    let initial_size = allocator::heap_size();
+    let vec = vec![0u8; HEAP_SIZE * 2];
+    assert_eq!(vec.len(), HEAP_SIZE * 2);
+    assert!(allocator::heap_size() > initial_size);
+    assert!(allocator::heap_high_water_mark() >= HEAP_SIZE * 2);
+}
+
+/// Allocations are aligned, whether they end up in a slab or in the large heap
+#[test_case]
+fn aligned_allocations() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    for &(size, align) in &[(1, 1), (24, 64), (100, 8), (8, 4096), (5000, 16), (3000, 8192)] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % align, 0);
+        unsafe { dealloc(ptr, layout) };
+    }
+}
+
+/// Allocations that are freed again don't show up as leaks, ones that are kept do
+#[cfg(feature = "alloc-tracking")]
+#[test_case]
+fn leak_detection() {
+    use kernel::allocator::tracking;
+
+    let before = tracking::snapshot();
+    let freed = vec![1u64; 16];
+    drop(freed);
+    let leaked = Box::leak(Box::new([0u8; 100]));
+    let after = tracking::snapshot();
+
+    let leaks = after.allocated_since(&before);
+    assert_eq!(leaks.len(), 1);
+    assert_eq!(leaks.records()[0].ptr, leaked.as_ptr() as usize);
+    assert_eq!(leaks.records()[0].size, 100);
+}
+
+/// Records stay findable while the table churns through many more allocations than it has slots
+#[cfg(feature = "alloc-tracking")]
+#[test_case]
+fn tracking_survives_churn() {
+    use kernel::allocator::tracking;
+
+    let kept: Vec<Box<u64>> = (0..64).map(Box::new).collect();
+    for i in 0..20_000u64 {
+        drop(Box::new(i));
+    }
+    for value in kept.iter() {
+        let ptr = &**value as *const u64 as usize;
+        assert_eq!(tracking::find(ptr).map(|record| record.ptr), Some(ptr));
+    }
+}
+
+/// The recorded call site of an allocation is the function that made it, not the `Box` and
+/// allocator functions in between
+#[cfg(feature = "alloc-tracking")]
+#[test_case]
+fn tracking_records_call_site() {
+    use kernel::allocator::tracking;
+    use kernel::backtrace::symbols;
+
+    assert!(symbols::count() > 0, "the symbol table was not embedded");
+    let value = Box::new(7u64);
+    let ptr = &*value as *const u64 as usize;
+    let record = tracking::find(ptr).expect("allocation was not recorded");
+    let symbol = symbols::resolve(record.callers[0] - 1).expect("call site has no symbol");
+    assert!(
+        symbol.name.ends_with("tracking_records_call_site"),
+        "call site resolved to {}", symbol.name
+    );
+}
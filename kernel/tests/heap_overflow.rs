@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use alloc::vec::Vec;
+
+use kernel::{QemuExitCode, exit_qemu, serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        *mapper = unsafe { Some(memory::init(phys_mem_offset)) };
+        *frame_allocator = unsafe {
+            Some(BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset))
+        };
+        allocator::init_heap(mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+            .expect("heap initialization failed");
+    }
+
+    serial_print!("heap_overflow::overflow_is_detected...\t");
+    overflow_is_detected();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+/// Writing one byte past the end of an allocation panics when it is freed
+fn overflow_is_detected() {
+    let mut vec: Vec<u8> = Vec::with_capacity(16);
+    unsafe { vec.as_mut_ptr().add(16).write(0x42); }
+    drop(vec);
+}
+
+/// The report `allocator::hardening` panics with for damage behind an allocation
+const EXPECTED_REPORT: &str = "HEAP CORRUPTION: overflow of the allocation";
+
+/// Keeps the start of a panic message, the heap can't be trusted to format it
+struct MessageBuffer {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl fmt::Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+impl MessageBuffer {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+/// Only the overflow report passes, any other panic is a failure
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = MessageBuffer { bytes: [0; 256], len: 0 };
+    let _ = write!(message, "{}", info);
+    if message.as_str().contains(EXPECTED_REPORT) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,166 @@
+#![no_std]
+#![no_main]
+
+#![feature(custom_test_frameworks)]
+#![feature(llvm_asm)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{BootInfo, entry_point};
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kernel::apic::SPURIOUS_VECTOR;
+use kernel::interrupts::dispatch::{self, IrqReturn, RegisterError, FIRST_DYNAMIC_VECTOR, LAST_DYNAMIC_VECTOR};
+use kernel::interrupts::spurious;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        *mapper = unsafe { Some(memory::init(phys_mem_offset)) };
+        *frame_allocator = unsafe {
+            Some(BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset))
+        };
+        allocator::init_heap(mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+            .expect("heap initialization failed");
+    }
+    dispatch::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+fn handled(_context: *mut ()) -> IrqReturn {
+    IrqReturn::Handled
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Shared handlers can be stacked on a vector, an exclusive one can't join them
+#[test_case]
+fn shared_and_exclusive_handlers() {
+    let vector = dispatch::allocate_vector().expect("no free vector");
+    let first = dispatch::register_fn(vector, "first", true, handled, ptr::null_mut()).unwrap();
+    let second = dispatch::register_fn(vector, "second", true, handled, ptr::null_mut()).unwrap();
+    assert_eq!(
+        dispatch::register_fn(vector, "exclusive", false, handled, ptr::null_mut()),
+        Err(RegisterError::Busy)
+    );
+    assert_eq!(dispatch::handler_names(vector), ["first", "second"]);
+
+    assert!(dispatch::unregister(first));
+    assert!(dispatch::unregister(second));
+    // Nothing can share a vector with an exclusive handler either
+    let exclusive = dispatch::register_fn(vector, "exclusive", false, handled, ptr::null_mut()).unwrap();
+    assert_eq!(
+        dispatch::register_fn(vector, "shared", true, handled, ptr::null_mut()),
+        Err(RegisterError::Busy)
+    );
+    assert!(dispatch::unregister(exclusive));
+    dispatch::free_vector(vector);
+}
+
+/// Exceptions and vectors with their own IDT entry can't get handlers
+#[test_case]
+fn reserved_vectors_are_invalid() {
+    assert_eq!(
+        dispatch::register_fn(SPURIOUS_VECTOR, "spurious", false, handled, ptr::null_mut()),
+        Err(RegisterError::InvalidVector)
+    );
+    assert_eq!(
+        dispatch::register_fn(14, "page fault", false, handled, ptr::null_mut()),
+        Err(RegisterError::InvalidVector)
+    );
+}
+
+/// Allocation hands out every dynamic vector once, and never a reserved one
+#[test_case]
+fn allocate_skips_reserved_vectors() {
+    let mut allocated = Vec::new();
+    while let Some(vector) = dispatch::allocate_vector() {
+        assert!((FIRST_DYNAMIC_VECTOR..=LAST_DYNAMIC_VECTOR).contains(&vector));
+        assert!(!allocated.contains(&vector), "vector {} handed out twice", vector);
+        allocated.push(vector);
+    }
+    assert!(!allocated.contains(&SPURIOUS_VECTOR));
+    assert_eq!(allocated.len(), usize::from(LAST_DYNAMIC_VECTOR - FIRST_DYNAMIC_VECTOR) + 1);
+    for vector in allocated {
+        dispatch::free_vector(vector);
+    }
+}
+
+/// A handler id stops working once the handler is unregistered
+#[test_case]
+fn unregister_stale_id() {
+    let vector = dispatch::allocate_vector().expect("no free vector");
+    let id = dispatch::register_fn(vector, "stale", false, handled, ptr::null_mut()).unwrap();
+    assert!(dispatch::unregister(id));
+    assert!(!dispatch::unregister(id));
+    dispatch::free_vector(vector);
+}
+
+/// A vector stays allocated while it has handlers, even if it is freed
+#[test_case]
+fn free_vector_with_handlers() {
+    let vector = dispatch::allocate_vector().expect("no free vector");
+    let id = dispatch::register_fn(vector, "busy", false, handled, ptr::null_mut()).unwrap();
+    dispatch::free_vector(vector);
+    let other = dispatch::allocate_vector().expect("no free vector");
+    assert_ne!(other, vector);
+    dispatch::free_vector(other);
+
+    assert!(dispatch::unregister(id));
+    dispatch::free_vector(vector);
+    // Allocation hands out the lowest free vector, which is this one again
+    assert_eq!(dispatch::allocate_vector(), Some(vector));
+    dispatch::free_vector(vector);
+}
+
+static SOFTWARE_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+fn count_software_interrupt(_context: *mut ()) -> IrqReturn {
+    SOFTWARE_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    IrqReturn::Handled
+}
+
+/// A software interrupt on a vector the APIC doesn't route reaches its handler, it isn't
+/// mistaken for a spurious interrupt just because it isn't in service
+#[test_case]
+fn software_interrupt_runs_handler() {
+    let id = dispatch::register_fn(LAST_DYNAMIC_VECTOR, "software", false, count_software_interrupt, ptr::null_mut())
+        .expect("vector 0xFE is taken");
+    let before = spurious::counts();
+    unsafe { llvm_asm!("int 0xFE" :::: "intel", "volatile"); }
+    assert_eq!(SOFTWARE_INTERRUPTS.load(Ordering::Relaxed), 1);
+    assert_eq!(spurious::counts(), before);
+    assert!(dispatch::unregister(id));
+    dispatch::free_vector(LAST_DYNAMIC_VECTOR);
+}
+
+/// The spurious vector of the local APIC is counted without an EOI
+#[test_case]
+fn local_apic_spurious_vector_is_counted() {
+    let before = spurious::counts().local_apic;
+    unsafe { llvm_asm!("int 0xFF" :::: "intel", "volatile"); }
+    assert_eq!(spurious::counts().local_apic, before + 1);
+}
@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+
+use core::panic::PanicInfo;
+
+use x86_64::VirtAddr;
+
+use kernel::gdt::{self, DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX, PAGE_FAULT_IST_INDEX};
+use kernel::memory::{self, MAPPER};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init();
+    memory::update_physical_memory_offset(boot_info.physical_memory_offset);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    *MAPPER.lock() = unsafe { Some(memory::init(phys_mem_offset)) };
+    gdt::add_ist_guard_pages(MAPPER.lock().as_mut().unwrap());
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+const IST_INDEXES: [u16; 4] = [DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX];
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    let offset = memory::PHYSICAL_MEMORY_OFFSET.load(core::sync::atomic::Ordering::Relaxed);
+    unsafe { memory::translate_addr(addr, VirtAddr::new(offset)).is_some() }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Every interrupt stack has an unmapped guard page right below its mapped bottom
+#[test_case]
+fn guard_pages_are_unmapped() {
+    for &index in IST_INDEXES.iter() {
+        let guard = gdt::ist_guard_page(index).start_address();
+        assert!(!is_mapped(guard), "guard page of IST {} is mapped", index);
+        assert!(is_mapped(guard + 4096u64), "bottom of IST {} is not mapped", index);
+    }
+}
+
+/// The stack range used to detect nested page faults covers the guard page, and only the
+/// stack it belongs to
+#[test_case]
+fn stack_contains_guard_page() {
+    let guard = gdt::ist_guard_page(PAGE_FAULT_IST_INDEX).start_address();
+    assert!(gdt::ist_stack_contains(PAGE_FAULT_IST_INDEX, guard));
+    assert!(gdt::ist_stack_contains(PAGE_FAULT_IST_INDEX, guard + 4096u64));
+    assert!(!gdt::ist_stack_contains(PAGE_FAULT_IST_INDEX, guard - 1u64));
+    let other = gdt::ist_guard_page(NMI_IST_INDEX).start_address();
+    assert!(!gdt::ist_stack_contains(PAGE_FAULT_IST_INDEX, other + 4096u64));
+}
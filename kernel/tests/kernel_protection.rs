@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+
+use core::panic::PanicInfo;
+use core::sync::atomic::AtomicU64;
+
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use kernel::memory::protection;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init();
+    kernel::memory::update_physical_memory_offset(boot_info.physical_memory_offset);
+    let physical_memory_size = boot_info.memory_map.iter().map(|r| r.range.end_addr()).max().unwrap_or(0);
+    protection::enforce_w_xor_x(physical_memory_size);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+/// Lands in .rodata
+static READ_ONLY: [u8; 64] = [0x5A; 64];
+/// Lands in .data or .bss
+static WRITABLE: AtomicU64 = AtomicU64::new(0);
+
+fn flags_of(addr: VirtAddr) -> PageTableFlags {
+    protection::page_flags(addr).expect("kernel image page not mapped with 4 KiB pages")
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Read-only data is neither executable nor writable, even where the linker puts it right
+/// after the code
+#[test_case]
+fn rodata_is_not_executable() {
+    let flags = flags_of(VirtAddr::from_ptr(&READ_ONLY));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE), "rodata page is executable: {:?}", flags);
+    assert!(!flags.contains(PageTableFlags::WRITABLE), "rodata page is writable: {:?}", flags);
+}
+
+#[test_case]
+fn code_is_not_writable() {
+    let flags = flags_of(VirtAddr::new(code_is_not_writable as usize as u64));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn data_is_not_executable() {
+    let flags = flags_of(VirtAddr::from_ptr(&WRITABLE));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+}
+
+/// The kernel can't write to read-only pages either, otherwise their flags wouldn't protect them
+#[test_case]
+fn write_protect_is_enabled() {
+    assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+}
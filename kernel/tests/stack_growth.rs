@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{BootInfo, entry_point};
+
+use core::panic::PanicInfo;
+
+use x86_64::VirtAddr;
+
+use kernel::memory::{self, BitmapFrameAllocator, StackBounds, FRAME_ALLOCATOR, MAPPER};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init();
+    memory::update_physical_memory_offset(boot_info.physical_memory_offset);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        *mapper = unsafe { Some(memory::init(phys_mem_offset)) };
+        *frame_allocator = unsafe {
+            Some(BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset))
+        };
+        kernel::allocator::init_heap(mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+            .expect("heap initialization failed");
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+const STACK_PAGES: u64 = 4;
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    let offset = memory::PHYSICAL_MEMORY_OFFSET.load(core::sync::atomic::Ordering::Relaxed);
+    unsafe { memory::translate_addr(addr, VirtAddr::new(offset)).is_some() }
+}
+
+/// Page `index` of the stack, counted from the bottom
+fn stack_page(bounds: StackBounds, index: u64) -> VirtAddr {
+    bounds.start() + index * 4096
+}
+
+fn free(bounds: StackBounds) {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    unsafe { memory::free_stack(bounds, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap()) }
+        .expect("failed to free stack");
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Test cases
+///////////////////////////////////////////////////////////////////////////////////////////////////
+/// Thread stacks are mapped completely, so they never fault
+#[test_case]
+fn eager_stack_is_mapped() {
+    let bounds = {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        memory::alloc_stack(STACK_PAGES, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+            .expect("failed to allocate stack")
+    };
+    for index in 0..STACK_PAGES {
+        assert!(is_mapped(stack_page(bounds, index)), "page {} of the stack is not mapped", index);
+    }
+    free(bounds);
+}
+
+/// Writing below the mapped top of a lazy stack goes through the page fault handler, which
+/// backs the page with a zeroed frame
+#[test_case]
+fn lazy_stack_grows() {
+    let bounds = {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        memory::alloc_lazy_stack(STACK_PAGES, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+            .expect("failed to allocate stack")
+    };
+    assert!(is_mapped(stack_page(bounds, STACK_PAGES - 1)));
+    let bottom = stack_page(bounds, 0);
+    assert!(!is_mapped(bottom));
+
+    // The mapper and frame allocator are unlocked, so the fault handler can take them
+    let ptr = bottom.as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(0xDEAD_BEEF);
+        assert_eq!(ptr.read_volatile(), 0xDEAD_BEEF);
+    }
+    assert!(is_mapped(bottom));
+    assert!(!is_mapped(stack_page(bounds, 1)));
+    free(bounds);
+}
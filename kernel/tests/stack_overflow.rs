@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{BootInfo, entry_point};
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+use x86_64::structures::paging::{Mapper, Page, Size4KiB};
+use x86_64::VirtAddr;
+
+use kernel::gdt::{self, PAGE_FAULT_IST_INDEX};
+use kernel::{QemuExitCode, exit_qemu, serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_overflow::stack_overflow...\t");
+
+    kernel::init();
+    kernel::memory::update_physical_memory_offset(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { kernel::memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    gdt::add_ist_guard_pages(&mut mapper);
+    break_page_fault_stack(&mut mapper);
+
+    // The page fault on the guard page of the boot stack can't be delivered, so it double faults
+    stack_overflow();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
+}
+
+/// Unmaps the top page of the page fault stack. A page fault runs on its own stack and would
+/// otherwise be handled as one, pushing its frame there now faults again, which the CPU turns
+/// into a double fault.
+fn break_page_fault_stack(mapper: &mut impl Mapper<Size4KiB>) {
+    let top: Page<Size4KiB> = Page::containing_address(gdt::ist_stack_top(PAGE_FAULT_IST_INDEX) - 1u64);
+    // The frame stays part of the kernel image, it is never handed out again
+    mapper.unmap(top).expect("failed to unmap the page fault stack").1.flush();
+}
+
+/// The panic of the double fault handler of the kernel
+const EXPECTED_PANIC: &str = "EXCEPTION: DOUBLE FAULT";
+
+/// Keeps the start of a panic message, there is no heap to format it into
+struct MessageBuffer {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl fmt::Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+impl MessageBuffer {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+/// Only the panic of the double fault handler passes, any other panic is a failure
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = MessageBuffer { bytes: [0; 256], len: 0 };
+    let _ = write!(message, "{}", info);
+    if message.as_str().contains(EXPECTED_PANIC) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,131 @@
+#!/usr/bin/env python3
+"""Fills the kernel symbol table (`KERNEL_SYMBOLS`, see kernel/src/backtrace/symbols.rs) of a
+linked kernel binary with the function symbols from its ELF symbol table.
+
+Usage: embed_symbols.py <kernel ELF>
+"""
+
+import re
+import struct
+import sys
+
+MAGIC = b"KSYMTAB\0"
+TABLE_SYMBOL = "KERNEL_SYMBOLS"
+
+SHT_SYMTAB = 2
+STT_FUNC = 2
+PT_LOAD = 1
+
+ENTRY = struct.Struct("<QQII")
+
+LEGACY_ESCAPES = {
+    "$SP$": "@", "$BP$": "*", "$RF$": "&", "$LT$": "<", "$GT$": ">",
+    "$LP$": "(", "$RP$": ")", "$C$": ",", "$u7e$": "~", "$u20$": " ",
+    "$u27$": "'", "$u5b$": "[", "$u5d$": "]", "$u7b$": "{", "$u7d$": "}",
+    "$u3b$": ";", "$u2b$": "+", "$u22$": '"',
+}
+
+
+def demangle(name):
+    """Demangles legacy Rust symbols, anything else is returned as is."""
+    match = re.fullmatch(r"_?_ZN(.*)E", name)
+    if not match:
+        return name
+    rest, parts = match.group(1), []
+    while rest:
+        length = re.match(r"\d+", rest)
+        if not length:
+            return name
+        start = length.end()
+        end = start + int(length.group())
+        parts.append(rest[start:end])
+        rest = rest[end:]
+    # The last part is a hash of the symbol
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+    demangled = "::".join(parts)
+    for escape, char in LEGACY_ESCAPES.items():
+        demangled = demangled.replace(escape, char)
+    return demangled.replace("..", "::")
+
+
+def read_elf(image):
+    if image[:4] != b"\x7fELF" or image[4] != 2:
+        sys.exit("not a 64 bit ELF file")
+    (phoff, shoff) = struct.unpack_from("<QQ", image, 0x20)
+    (phentsize, phnum, shentsize, shnum) = struct.unpack_from("<HHHH", image, 0x36)
+    sections = [struct.unpack_from("<IIQQQQIIQQ", image, shoff + i * shentsize) for i in range(shnum)]
+    segments = [struct.unpack_from("<IIQQQQQQ", image, phoff + i * phentsize) for i in range(phnum)]
+    return sections, segments
+
+
+def read_symbols(image, sections):
+    symtab = next((s for s in sections if s[1] == SHT_SYMTAB), None)
+    if symtab is None:
+        sys.exit("the kernel has no symbol table, is it stripped?")
+    strtab = sections[symtab[6]]
+    symbols = []
+    for offset in range(symtab[4], symtab[4] + symtab[5], symtab[9]):
+        (name, info, _, _, value, size) = struct.unpack_from("<IBBHQQ", image, offset)
+        start = strtab[4] + name
+        end = image.index(b"\0", start)
+        symbols.append((image[start:end].decode(), info & 0xF, value, size))
+    return symbols
+
+
+def file_offset(segments, address):
+    for (kind, _, offset, vaddr, _, filesz, _, _) in segments:
+        if kind == PT_LOAD and vaddr <= address < vaddr + filesz:
+            return offset + address - vaddr
+    sys.exit("{} is not backed by the file".format(TABLE_SYMBOL))
+
+
+def build_table(functions, capacity):
+    names, name_offsets = b"", []
+    strings_start = 8 + len(functions) * ENTRY.size
+    for (name, _, _) in functions:
+        name_offsets.append(strings_start + len(names))
+        names += name.encode()
+    data = struct.pack("<Q", len(functions))
+    for (name, address, size), name_offset in zip(functions, name_offsets):
+        data += ENTRY.pack(address, size, name_offset, len(name.encode()))
+    data += names
+    if len(data) > capacity:
+        sys.exit("the symbol table needs {} bytes, but only {} are reserved".format(len(data), capacity))
+    return MAGIC + data
+
+
+def embed(path):
+    """Fills the symbol table of the kernel binary at `path` in place."""
+    with open(path, "rb") as file:
+        image = bytearray(file.read())
+
+    sections, segments = read_elf(image)
+    symbols = read_symbols(image, sections)
+    table = next((s for s in symbols if s[0] == TABLE_SYMBOL), None)
+    if table is None:
+        sys.exit("{} not found in {}".format(TABLE_SYMBOL, path))
+
+    functions = {}
+    for (name, kind, address, size) in symbols:
+        if kind == STT_FUNC and address != 0:
+            functions.setdefault(address, (demangle(name), address, size))
+    functions = sorted(functions.values(), key=lambda function: function[1])
+
+    (_, _, address, size) = table
+    data = build_table(functions, size - len(MAGIC))
+    offset = file_offset(segments, address)
+    image[offset:offset + len(data)] = data
+    with open(path, "wb") as file:
+        file.write(image)
+    print("embedded {} symbols ({} bytes) into {}".format(len(functions), len(data), path))
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(__doc__)
+    embed(sys.argv[1])
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,24 @@
+#!/usr/bin/env python3
+"""Cargo runner of the kernel: fills the symbol table of the kernel binary, so backtraces show
+function names, and hands the binary on to `bootimage runner`.
+
+Usage: runner.py <kernel ELF> [arguments for the kernel...]
+"""
+
+import os
+import subprocess
+import sys
+
+sys.path.insert(0, os.path.dirname(os.path.abspath(__file__)))
+import embed_symbols  # noqa: E402
+
+
+def main():
+    if len(sys.argv) < 2:
+        sys.exit(__doc__)
+    embed_symbols.embed(sys.argv[1])
+    sys.exit(subprocess.call(["bootimage", "runner"] + sys.argv[1:]))
+
+
+if __name__ == "__main__":
+    main()